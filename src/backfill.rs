@@ -0,0 +1,754 @@
+use chrono::{NaiveDateTime, Utc};
+use crate::cluster::default_instance_id;
+use crate::cmdargs::{LimitOverrides, TimePeriod};
+use crate::downsampling::downsample_period;
+use crate::influx::influx_client;
+use crate::listen::render_measurement_name;
+use crate::progress::Outcome;
+use crate::schedule::bucket_ends;
+use crate::selection::Selection;
+use crate::settings::{Config, Interval};
+use crate::utils::error::{print_err_and_exit, Context, Error};
+use crate::utils::shutdown;
+use crate::utils::time::from_timestamp_nanos;
+use humantime::format_duration;
+use influx_db_client::Client;
+use log::{debug, error, info, warn};
+use redis::{Commands, Connection, RedisResult, Script};
+use std::collections::HashMap;
+use std::iter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+use string_template::Template;
+use time::Duration;
+
+// a backfill spread over machines through Redis. the coordinator enqueues one job per id, interval and chunk
+// of `backfill.chunk`, any number of workers claim jobs with a lease, downsample their buckets and mark them
+// done. a job whose worker stops renewing its lease is handed out again.
+
+const QUEUE_LIST_NAME: &str = "downsampler_backfill_queue";
+// jobs handed out to a worker, each with a lease key while its worker is alive
+const LEASED_SET_NAME: &str = "downsampler_backfill_leased";
+const FAILED_LIST_NAME: &str = "downsampler_backfill_failed";
+// the period and counters of the backfill
+const STATE_HASH_NAME: &str = "downsampler_backfill";
+const LEASE_KEY_PREFIX: &str = "downsampler_backfill_lease:";
+
+// jobs pushed per round trip while enqueueing
+const ENQUEUE_BATCH: usize = 1000;
+// how often the coordinator logs the progress
+const PROGRESS_INTERVAL: StdDuration = StdDuration::from_secs(10);
+// how long a worker waits before asking for a job again, when there was none
+const IDLE_SLEEP: StdDuration = StdDuration::from_secs(1);
+
+// KEYS: queue, leased set, ARGV: worker id, lease ttl in ms, lease key prefix.
+// claims a leased job whose worker is gone, or else the next queued one
+const CLAIM_JOB_SCRIPT: &str = r"
+local job = false
+for _, leased in ipairs(redis.call('SMEMBERS', KEYS[2])) do
+    if redis.call('EXISTS', ARGV[3] .. leased) == 0 then
+        job = leased
+        break
+    end
+end
+if not job then
+    job = redis.call('LPOP', KEYS[1])
+    if not job then
+        return false
+    end
+    redis.call('SADD', KEYS[2], job)
+end
+redis.call('SET', ARGV[3] .. job, ARGV[1], 'PX', ARGV[2])
+return job
+";
+
+// KEYS: lease key, ARGV: worker id, lease ttl in ms. renews the lease if it's still ours
+const RENEW_LEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+    return 1
+end
+return 0
+";
+
+// KEYS: lease key, leased set, state hash, list to push to, ARGV: worker id, job, counter to increment or '',
+// points read, points written, entry to push or ''. settles a job, unless its lease was lost to another worker
+const FINISH_JOB_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('DEL', KEYS[1])
+redis.call('SREM', KEYS[2], ARGV[2])
+if ARGV[3] ~= '' then
+    redis.call('HINCRBY', KEYS[3], ARGV[3], 1)
+end
+redis.call('HINCRBY', KEYS[3], 'points_in', ARGV[4])
+redis.call('HINCRBY', KEYS[3], 'points_out', ARGV[5])
+if ARGV[6] ~= '' then
+    redis.call('RPUSH', KEYS[4], ARGV[6])
+end
+return 1
+";
+
+// KEYS: lease key, ARGV: worker id. gives the job back right away instead of waiting for the lease to expire
+const RELEASE_LEASE_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+// the buckets of `interval` of `id` ending in [start, end), in nanoseconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub interval: String,
+    pub start: i64,
+    pub end: i64,
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FailedJob {
+    job: Job,
+    error: String,
+    failed_at: String,
+}
+
+pub enum BackfillAction {
+    // enqueues the jobs of the period, then follows the progress until the workers are done
+    Coordinate(TimePeriod, Selection),
+    // claims and runs jobs until the backfill is done, the limits apply to this machine
+    Work(LimitOverrides),
+    Status,
+    Clear,
+}
+
+// the backfill as stored in Redis
+struct Status {
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    // `None` while the coordinator is still enqueueing
+    total: Option<u64>,
+    done: u64,
+    failed: u64,
+    queued: u64,
+    leased: u64,
+    points_in: u64,
+    points_out: u64,
+    enqueued_at: Option<NaiveDateTime>,
+}
+
+impl Status {
+    fn finished(&self) -> bool {
+        self.total.is_some() && self.queued == 0 && self.leased == 0
+    }
+
+    fn outcome(&self) -> Outcome {
+        if self.failed == 0 {
+            Outcome::Completed
+        } else if self.done > 0 {
+            Outcome::PartiallyFailed
+        } else {
+            Outcome::Failed
+        }
+    }
+
+    fn summary(&self) -> String {
+        let total = self.total.unwrap_or(0);
+        let settled = self.done + self.failed;
+        let eta = match self.enqueued_at {
+            Some(enqueued_at) if settled > 0 && total > settled => {
+                let elapsed = (Utc::now().naive_utc() - enqueued_at).num_seconds().max(0) as u64;
+                format_duration(StdDuration::from_secs(elapsed * (total - settled) / settled)).to_string()
+            }
+            _ => "unknown".to_owned(),
+        };
+
+        format!(
+            "Backfill {} - {}: {}/{} jobs done, {} failed, {} leased, {} queued, {} points read, {} points written, ETA {}",
+            self.start,
+            self.end,
+            self.done,
+            total,
+            self.failed,
+            self.leased,
+            self.queued,
+            self.points_in,
+            self.points_out,
+            eta
+        )
+    }
+}
+
+// the Redis keys of the backfill, per job of the config
+struct Keys {
+    queue: String,
+    leased: String,
+    failed: String,
+    state: String,
+    lease_prefix: String,
+}
+
+impl Keys {
+    fn new(config: &Config) -> Keys {
+        Keys {
+            queue: config.redis_key(QUEUE_LIST_NAME),
+            leased: config.redis_key(LEASED_SET_NAME),
+            failed: config.redis_key(FAILED_LIST_NAME),
+            state: config.redis_key(STATE_HASH_NAME),
+            lease_prefix: config.redis_key(LEASE_KEY_PREFIX),
+        }
+    }
+
+    // leases are keyed by the job as it was enqueued
+    fn lease(&self, raw: &str) -> String {
+        format!("{}{}", self.lease_prefix, raw)
+    }
+}
+
+// only `coordinate` has an outcome, the workers exit once there's nothing left to do
+pub fn backfill(action: &BackfillAction, config: &Config) -> Option<Outcome> {
+    let keys = Keys::new(config);
+    let client = redis::Client::open(config.listen.redis_url.as_str())
+        .unwrap_or_else(|e| print_err_and_exit(e));
+    let con = client
+        .get_connection()
+        .unwrap_or_else(|e| print_err_and_exit(e));
+
+    match action {
+        BackfillAction::Coordinate(period, _) => Some(coordinate(&con, &keys, period, config)),
+        BackfillAction::Work(_) => {
+            // as many workers as `downsample` would use threads
+            let workers = rayon::current_num_threads();
+            let instance_id = default_instance_id();
+            info!("Starting {} backfill workers as {}", workers, instance_id);
+            thread::scope(|scope| {
+                for i in 0..workers {
+                    let worker_id = format!("{}-{}", instance_id, i);
+                    let (client, keys) = (&client, &keys);
+                    scope.spawn(move || work(client, keys, &worker_id, config));
+                }
+            });
+            None
+        }
+        BackfillAction::Status => {
+            match get_status(&con, &keys).unwrap_or_else(|e| print_err_and_exit(e)) {
+                Some(status) => println!("{}", status.summary()),
+                None => println!("No backfill enqueued"),
+            }
+            for failed in get_failed(&con, &keys).unwrap_or_else(|e| print_err_and_exit(e)) {
+                println!(
+                    "failed: {} {} [{} - {}] attempts: {}, failed at: {}, error: {}",
+                    failed.job.id,
+                    failed.job.interval,
+                    from_timestamp_nanos(failed.job.start),
+                    from_timestamp_nanos(failed.job.end),
+                    failed.job.attempts,
+                    failed.failed_at,
+                    failed.error
+                );
+            }
+            None
+        }
+        BackfillAction::Clear => {
+            clear(&con, &keys).unwrap_or_else(|e| print_err_and_exit(e));
+            println!("Removed the backfill");
+            None
+        }
+    }
+}
+
+fn coordinate(con: &Connection, keys: &Keys, period: &TimePeriod, config: &Config) -> Outcome {
+    match get_status(con, keys).unwrap_or_else(|e| print_err_and_exit(e)) {
+        Some(status) => warn!(
+            "A backfill of {} - {} is already enqueued, following its progress. `backfill clear` removes it",
+            status.start, status.end
+        ),
+        None => {
+            let jobs = backfill_jobs(
+                &config.vars.ids,
+                &config.downsampler.intervals,
+                period.start,
+                period.end,
+                Duration::seconds(config.backfill.chunk_secs as i64),
+            );
+            info!(
+                "Enqueueing the jobs of {} ids and {} intervals",
+                config.vars.ids.len(),
+                config.downsampler.intervals.len()
+            );
+            let total = enqueue(con, keys, jobs, period.start, period.end)
+                .unwrap_or_else(|e| print_err_and_exit(e));
+            info!("Enqueued {} jobs", total);
+        }
+    }
+
+    let mut last_logged = Instant::now();
+    loop {
+        match get_status(con, keys) {
+            Ok(Some(status)) if status.finished() => {
+                info!("{}", status.summary());
+                return status.outcome();
+            }
+            Ok(Some(status)) if last_logged.elapsed() >= PROGRESS_INTERVAL => {
+                info!("{}", status.summary());
+                last_logged = Instant::now();
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                warn!("The backfill was removed");
+                return Outcome::Interrupted;
+            }
+            Err(e) => warn!("Failed to get the backfill status: {}", e),
+        }
+        if shutdown::requested() {
+            info!("Stopped following the backfill, the workers carry on. `backfill status` shows the progress");
+            return Outcome::Interrupted;
+        }
+        shutdown::sleep(IDLE_SLEEP);
+    }
+}
+
+// the jobs of a backfill over [start, end), the oldest chunk of every id first. generated while they are
+// enqueued, as a long period of many ids has millions of them. chunks without a bucket of an interval have no job.
+fn backfill_jobs<'a>(
+    ids: &'a [String],
+    intervals: &'a [Interval],
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    chunk: Duration,
+) -> impl Iterator<Item = Job> + 'a {
+    let chunks = iter::successors(Some(start), move |chunk_start| Some(*chunk_start + chunk))
+        .take_while(move |chunk_start| *chunk_start < end)
+        .map(move |chunk_start| (chunk_start, (chunk_start + chunk).min(end)));
+
+    chunks.flat_map(move |(chunk_start, chunk_end)| {
+        ids.iter().flat_map(move |id| {
+            intervals
+                .iter()
+                .filter(move |interval_period| {
+                    bucket_ends(chunk_start, chunk_end, interval_period).next().is_some()
+                })
+                .map(move |interval_period| Job {
+                    id: id.clone(),
+                    interval: interval_period.name.clone(),
+                    start: chunk_start.timestamp_nanos(),
+                    end: chunk_end.timestamp_nanos(),
+                    attempts: 0,
+                })
+        })
+    })
+}
+
+// the period is written first, so that another coordinator sees the backfill, and the total last:
+// workers only consider the backfill done once it's there. returns the number of jobs
+fn enqueue(
+    con: &Connection,
+    keys: &Keys,
+    jobs: impl Iterator<Item = Job>,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> RedisResult<u64> {
+    con.hset_multiple::<_, _, _, ()>(
+        &keys.state,
+        &[
+            ("start", start.timestamp_nanos()),
+            ("end", end.timestamp_nanos()),
+            ("enqueued_at", Utc::now().naive_utc().timestamp_nanos()),
+        ],
+    )?;
+
+    let mut total = 0;
+    let mut batch = Vec::with_capacity(ENQUEUE_BATCH);
+    for job in jobs {
+        batch.push(serde_json::to_string(&job).unwrap());
+        if batch.len() == ENQUEUE_BATCH {
+            con.rpush::<_, _, ()>(&keys.queue, &batch[..])?;
+            total += batch.len() as u64;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        con.rpush::<_, _, ()>(&keys.queue, &batch[..])?;
+        total += batch.len() as u64;
+    }
+
+    con.hset::<_, _, _, ()>(&keys.state, "total", total as i64)?;
+
+    Ok(total)
+}
+
+fn get_status(con: &Connection, keys: &Keys) -> RedisResult<Option<Status>> {
+    let state: HashMap<String, i64> = con.hgetall(&keys.state)?;
+    let (start, end) = match (state.get("start"), state.get("end")) {
+        (Some(start), Some(end)) => (from_timestamp_nanos(*start), from_timestamp_nanos(*end)),
+        _ => return Ok(None),
+    };
+    let counter = |name: &str| state.get(name).map_or(0, |n| *n as u64);
+
+    Ok(Some(Status {
+        start,
+        end,
+        total: state.get("total").map(|n| *n as u64),
+        done: counter("done"),
+        failed: counter("failed"),
+        queued: con.llen(&keys.queue)?,
+        leased: con.scard(&keys.leased)?,
+        points_in: counter("points_in"),
+        points_out: counter("points_out"),
+        enqueued_at: state.get("enqueued_at").map(|ts| from_timestamp_nanos(*ts)),
+    }))
+}
+
+fn get_failed(con: &Connection, keys: &Keys) -> RedisResult<Vec<FailedJob>> {
+    let entries: Vec<String> = con.lrange(&keys.failed, 0, -1)?;
+
+    Ok(entries
+        .into_iter()
+        .filter_map(|raw| match serde_json::from_str(&raw) {
+            Ok(failed) => Some(failed),
+            Err(e) => {
+                warn!("Skipping malformed failed job {:?}: {}", raw, e);
+                None
+            }
+        })
+        .collect())
+}
+
+fn clear(con: &Connection, keys: &Keys) -> RedisResult<()> {
+    let leased: Vec<String> = con.smembers(&keys.leased)?;
+    let mut removed: Vec<String> = leased.iter().map(|job| keys.lease(job)).collect();
+    removed.extend(
+        [&keys.queue, &keys.leased, &keys.failed, &keys.state]
+            .iter()
+            .map(|key| key.to_string()),
+    );
+    con.del::<_, ()>(removed)
+}
+
+// claims and runs jobs until the backfill is done or shutdown is requested.
+// a worker keeps its connections, one for the jobs and one to renew their leases, and reconnects after an error.
+fn work(client: &redis::Client, keys: &Keys, worker_id: &str, config: &Config) {
+    let influx = influx_client(
+        &config.influxdb.url,
+        &config.influxdb.db,
+        &config.influxdb.username,
+        &config.influxdb.pass,
+    );
+    let query_template = Template::new(&config.downsampler.query_template);
+    let measurement_template = Template::new(&config.downsampler.measurement_template);
+    let lease_ttl_ms = config.backfill.lease_ttl_ms;
+    let mut con: Option<Connection> = None;
+    let mut renew_con: Option<Connection> = None;
+
+    while !shutdown::requested() {
+        if con.is_none() {
+            match client.get_connection() {
+                Ok(connected) => con = Some(connected),
+                Err(e) => {
+                    warn!(worker = worker_id; "Failed to connect to Redis: {}", e);
+                    shutdown::sleep(IDLE_SLEEP);
+                    continue;
+                }
+            }
+        }
+        let connected = con.as_ref().unwrap();
+
+        let raw = match claim_job(connected, keys, worker_id, lease_ttl_ms) {
+            Ok(Some(raw)) => raw,
+            Ok(None) => {
+                match get_status(connected, keys) {
+                    Ok(Some(ref status)) if status.finished() => {
+                        info!(worker = worker_id; "The backfill is done");
+                        return;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!(worker = worker_id; "Failed to get the backfill status: {}", e);
+                        con = None;
+                    }
+                }
+                shutdown::sleep(IDLE_SLEEP);
+                continue;
+            }
+            Err(e) => {
+                warn!(worker = worker_id; "Failed to claim a backfill job: {}", e);
+                con = None;
+                shutdown::sleep(IDLE_SLEEP);
+                continue;
+            }
+        };
+
+        let job: Job = match serde_json::from_str(&raw) {
+            Ok(job) => job,
+            Err(e) => {
+                error!(worker = worker_id; "Dropping malformed backfill job {:?}: {}", raw, e);
+                let _ = finish_job(connected, keys, worker_id, &raw, "", (0, 0), None);
+                continue;
+            }
+        };
+        debug!(worker = worker_id, id = job.id.as_str(), interval = job.interval.as_str(); "claimed");
+
+        let lease = Lease {
+            key: keys.lease(&raw),
+            worker_id,
+            ttl_ms: lease_ttl_ms,
+        };
+        let res = with_lease(client, &mut renew_con, &lease, |lost| {
+            run_job(&influx, &query_template, &measurement_template, lost, &job, config)
+        });
+        let settled = match res {
+            Ok(JobResult::Done(points_in, points_out)) => {
+                finish_job(connected, keys, worker_id, &raw, "done", (points_in, points_out), None)
+            }
+            Ok(JobResult::Stopped) => {
+                // given back for another worker to redo
+                release_job(connected, keys, worker_id, &raw).map(|_| true)
+            }
+            Ok(JobResult::LeaseLost) => Ok(false),
+            Err(e) => {
+                error!(worker = worker_id; "{}", e);
+                let retried = Job {
+                    attempts: job.attempts + 1,
+                    ..job
+                };
+                if retried.attempts < config.backfill.max_attempts {
+                    let entry = serde_json::to_string(&retried).unwrap();
+                    finish_job(connected, keys, worker_id, &raw, "", (0, 0), Some((&keys.queue, &entry)))
+                } else {
+                    let failed = FailedJob {
+                        job: retried,
+                        error: e.to_string(),
+                        failed_at: Utc::now().to_rfc3339(),
+                    };
+                    let entry = serde_json::to_string(&failed).unwrap();
+                    finish_job(connected, keys, worker_id, &raw, "failed", (0, 0), Some((&keys.failed, &entry)))
+                }
+            }
+        };
+        match settled {
+            Ok(true) => {}
+            Ok(false) => warn!(worker = worker_id; "Lost the lease of a backfill job to another worker"),
+            // the lease expires and another worker redoes the job
+            Err(e) => {
+                warn!(worker = worker_id; "Failed to settle a backfill job: {}", e);
+                con = None;
+            }
+        }
+    }
+}
+
+// the lease a worker holds on the job it runs
+struct Lease<'a> {
+    key: String,
+    worker_id: &'a str,
+    ttl_ms: u64,
+}
+
+enum JobResult {
+    // points read and written
+    Done(usize, usize),
+    // shutdown was requested
+    Stopped,
+    // the lease expired and another worker claimed the job
+    LeaseLost,
+}
+
+// runs `job` while renewing the lease from another thread, so that a bucket that takes longer than the lease,
+// e.g. a busy id or a query held back by the rate limits, doesn't let another worker claim the job.
+// `job` is told through the flag when the lease was lost.
+fn with_lease<T, F>(client: &redis::Client, con: &mut Option<Connection>, lease: &Lease, job: F) -> T
+where
+    F: FnOnce(&AtomicBool) -> T,
+{
+    let lost = AtomicBool::new(false);
+    let (stop, stopped) = mpsc::channel::<()>();
+    thread::scope(|scope| {
+        let lost = &lost;
+        scope.spawn(move || renew_periodically(client, con, lease, lost, stopped));
+        let res = job(lost);
+        drop(stop);
+        res
+    })
+}
+
+fn renew_periodically(
+    client: &redis::Client,
+    con: &mut Option<Connection>,
+    lease: &Lease,
+    lost: &AtomicBool,
+    stopped: Receiver<()>,
+) {
+    let every = StdDuration::from_millis(lease.ttl_ms / 3);
+    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(every) {
+        if con.is_none() {
+            *con = client
+                .get_connection()
+                .map_err(|e| warn!(worker = lease.worker_id; "Failed to connect to Redis: {}", e))
+                .ok();
+        }
+        let renewed = match con.as_ref() {
+            Some(connected) => renew_lease(connected, lease),
+            None => continue,
+        };
+        match renewed {
+            Ok(true) => {}
+            Ok(false) => {
+                lost.store(true, Ordering::SeqCst);
+                return;
+            }
+            Err(e) => {
+                warn!(worker = lease.worker_id; "Failed to renew the lease of a backfill job: {}", e);
+                *con = None;
+            }
+        }
+    }
+}
+
+// downsamples the buckets of the job, until its lease is lost
+fn run_job(
+    influx: &Client,
+    query_template: &Template,
+    measurement_template: &Template,
+    lease_lost: &AtomicBool,
+    job: &Job,
+    config: &Config,
+) -> Result<JobResult, Error> {
+    let context = |period| Context::new(&job.id, Some(&job.interval), period);
+    let start = from_timestamp_nanos(job.start);
+    let interval_period = config
+        .downsampler
+        .intervals
+        .iter()
+        .find(|interval| interval.name == job.interval)
+        .ok_or_else(|| Error::UnknownInterval(context(start)))?;
+    let measurement_name = render_measurement_name(&job.id, measurement_template, &job.interval);
+
+    let (mut points_in, mut points_out) = (0, 0);
+    for bucket_end in bucket_ends(start, from_timestamp_nanos(job.end), interval_period) {
+        if shutdown::requested() {
+            return Ok(JobResult::Stopped);
+        }
+        if lease_lost.load(Ordering::SeqCst) {
+            return Ok(JobResult::LeaseLost);
+        }
+
+        let (read, written) = downsample_period(
+            config,
+            influx,
+            query_template,
+            &job.id,
+            bucket_end,
+            interval_period,
+            &measurement_name,
+        )
+        .map_err(|e| Error::influx(context(bucket_end), e))?;
+        points_in += read;
+        points_out += written;
+    }
+
+    Ok(JobResult::Done(points_in, points_out))
+}
+
+fn claim_job(con: &Connection, keys: &Keys, worker_id: &str, lease_ttl_ms: u64) -> RedisResult<Option<String>> {
+    Script::new(CLAIM_JOB_SCRIPT)
+        .key(&keys.queue)
+        .key(&keys.leased)
+        .arg(worker_id)
+        .arg(lease_ttl_ms)
+        .arg(&keys.lease_prefix)
+        .invoke(con)
+}
+
+fn renew_lease(con: &Connection, lease: &Lease) -> RedisResult<bool> {
+    let renewed: i64 = Script::new(RENEW_LEASE_SCRIPT)
+        .key(&lease.key)
+        .arg(lease.worker_id)
+        .arg(lease.ttl_ms)
+        .invoke(con)?;
+
+    Ok(renewed == 1)
+}
+
+// settles the job, counting it under `counter` along with the points read and written, and pushing `push`
+// as (list, entry) if given. returns false if the lease was lost to another worker meanwhile.
+fn finish_job(
+    con: &Connection,
+    keys: &Keys,
+    worker_id: &str,
+    raw: &str,
+    counter: &str,
+    (points_in, points_out): (usize, usize),
+    push: Option<(&str, &str)>,
+) -> RedisResult<bool> {
+    let (list, entry) = push.unwrap_or((&keys.failed, ""));
+    let finished: i64 = Script::new(FINISH_JOB_SCRIPT)
+        .key(keys.lease(raw))
+        .key(&keys.leased)
+        .key(&keys.state)
+        .key(list)
+        .arg(worker_id)
+        .arg(raw)
+        .arg(counter)
+        .arg(points_in)
+        .arg(points_out)
+        .arg(entry)
+        .invoke(con)?;
+
+    Ok(finished == 1)
+}
+
+fn release_job(con: &Connection, keys: &Keys, worker_id: &str, raw: &str) -> RedisResult<()> {
+    Script::new(RELEASE_LEASE_SCRIPT)
+        .key(keys.lease(raw))
+        .arg(worker_id)
+        .invoke(con)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::{schedule, Order};
+    use crate::settings::IntervalKind;
+
+    fn interval(name: &str, bucket_secs: u64) -> Interval {
+        Interval {
+            name: name.to_owned(),
+            kind: IntervalKind::Fixed,
+            bucket_secs,
+            points_per_bucket: 60,
+            offset_secs: 0,
+        }
+    }
+
+    #[test]
+    fn test_jobs_cover_the_schedule() {
+        let intervals = vec![interval("1m", 60), interval("1h", 3600)];
+        let ids = vec!["a".to_owned(), "b".to_owned()];
+        let start = NaiveDateTime::from_timestamp(30, 0);
+        let end = NaiveDateTime::from_timestamp(2 * 3600 + 30, 0);
+
+        let jobs: Vec<Job> = backfill_jobs(&ids, &intervals, start, end, Duration::minutes(45)).collect();
+        // 1h has no bucket ending in the first chunk
+        assert_eq!(jobs.len(), 2 + 2 * 2 + 2 * 2);
+        assert_eq!((jobs[0].id.as_str(), jobs[1].id.as_str()), ("a", "b"));
+
+        let mut covered: Vec<(String, String, NaiveDateTime)> = vec![];
+        for job in jobs.iter() {
+            let interval_period = intervals.iter().find(|i| i.name == job.interval).unwrap();
+            for bucket_end in bucket_ends(from_timestamp_nanos(job.start), from_timestamp_nanos(job.end), interval_period) {
+                covered.push((job.id.clone(), job.interval.clone(), bucket_end));
+            }
+        }
+        let mut expected: Vec<(String, String, NaiveDateTime)> = schedule(&ids, &intervals, start, end, Order::Id, None)
+            .map(|unit| (unit.id.to_owned(), unit.interval_period.name.clone(), unit.end))
+            .collect();
+        covered.sort();
+        expected.sort();
+        assert_eq!(covered, expected);
+    }
+}
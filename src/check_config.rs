@@ -0,0 +1,387 @@
+use chrono::Utc;
+use crate::influx::{from_json_values, get_range, influx_client, Error as InfluxError};
+use crate::settings::{Config, Field, FieldDataType, Interval, IntervalKind};
+use crate::{downsampling, listen, splitter};
+use std::collections::HashMap;
+use string_template::Template;
+use time::Duration;
+
+// how many of the configured ids the templates are rendered with
+const SAMPLE_IDS: usize = 3;
+
+const QUERY_PLACEHOLDERS: &[&str] = &["id", "start", "end", "limit", "time_interval"];
+const SPLITTER_QUERY_PLACEHOLDERS: &[&str] = &["id", "start", "end", "limit"];
+const MEASUREMENT_PLACEHOLDERS: &[&str] = &["id", "time_interval"];
+const SPLITTER_MEASUREMENT_PLACEHOLDERS: &[&str] = &["id"];
+
+pub struct CheckConfigArgs {
+    // also run every query against InfluxDB
+    pub query: bool,
+}
+
+// prints what was checked and every problem found, returns whether the config is valid
+pub fn check_config(args: &CheckConfigArgs, config: &Config) -> bool {
+    let mut problems = vec![];
+
+    let sample_ids: Vec<&str> = config
+        .vars
+        .ids
+        .iter()
+        .take(SAMPLE_IDS)
+        .map(|id| id.as_str())
+        .collect();
+    if sample_ids.is_empty() {
+        problems.push("vars.ids is empty".to_owned());
+    }
+
+    let templates = [
+        (
+            "downsampler.query_template",
+            &config.downsampler.query_template,
+            QUERY_PLACEHOLDERS,
+        ),
+        (
+            "downsampler.measurement_template",
+            &config.downsampler.measurement_template,
+            MEASUREMENT_PLACEHOLDERS,
+        ),
+        (
+            "listen.query_template",
+            &config.listen.query_template,
+            QUERY_PLACEHOLDERS,
+        ),
+        (
+            "listen.measurement_template",
+            &config.listen.measurement_template,
+            MEASUREMENT_PLACEHOLDERS,
+        ),
+        (
+            "splitter.query_template",
+            &config.splitter.query_template,
+            SPLITTER_QUERY_PLACEHOLDERS,
+        ),
+        (
+            "splitter.measurement_template",
+            &config.splitter.measurement_template,
+            SPLITTER_MEASUREMENT_PLACEHOLDERS,
+        ),
+    ];
+    for (name, template, known) in templates.iter() {
+        for placeholder in placeholders(template) {
+            if !known.contains(&placeholder) {
+                problems.push(format!(
+                    "{} has an unknown placeholder {{{{{}}}}}, known are {:?}",
+                    name, placeholder, known
+                ));
+            }
+        }
+    }
+
+    for id in sample_ids.iter() {
+        println!("Rendered for {}:", id);
+        for interval_period in config.downsampler.intervals.iter() {
+            println!(
+                "  downsampler measurement {}: {}",
+                interval_period.name,
+                listen::render_measurement_name(
+                    id,
+                    &Template::new(&config.downsampler.measurement_template),
+                    &interval_period.name
+                )
+            );
+            println!(
+                "  listen measurement {}: {}",
+                interval_period.name,
+                listen::render_measurement_name(
+                    id,
+                    &Template::new(&config.listen.measurement_template),
+                    &interval_period.name
+                )
+            );
+        }
+        println!(
+            "  splitter measurement: {}",
+            splitter::make_measurement_name(
+                &Template::new(&config.splitter.measurement_template),
+                id
+            )
+        );
+        for (name, query) in sample_queries(config, id, 0).iter() {
+            println!("  {}: {}", name, query.trim());
+        }
+    }
+
+    problems.extend(check_field_index(
+        "downsampler.x_field_index",
+        config.downsampler.x_field_index,
+        &config.downsampler.fields,
+    ));
+    problems.extend(check_field_index(
+        "downsampler.y_field_index",
+        config.downsampler.y_field_index,
+        &config.downsampler.fields,
+    ));
+    problems.extend(check_intervals(&config.downsampler.intervals));
+
+    if args.query {
+        if let Some(id) = sample_ids.first() {
+            problems.extend(check_queries(config, id));
+        }
+    }
+
+    if problems.is_empty() {
+        println!("Config is valid");
+    } else {
+        println!("Found {} problems:", problems.len());
+        for problem in problems.iter() {
+            println!("  {}", problem);
+        }
+    }
+
+    problems.is_empty()
+}
+
+// names between `{{` and `}}`, as `string_template` matches them
+fn placeholders(template: &str) -> Vec<&str> {
+    let mut found = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rest = &rest[start + 2..];
+        match rest.find('}') {
+            Some(end) if rest[end..].starts_with("}}") => {
+                found.push(&rest[..end]);
+                rest = &rest[end + 2..];
+            }
+            _ => break,
+        }
+    }
+
+    found
+}
+
+fn check_field_index(name: &str, index: usize, fields: &[Field]) -> Option<String> {
+    match fields.get(index) {
+        None => Some(format!(
+            "{} is {}, but there are only {} fields",
+            name,
+            index,
+            fields.len()
+        )),
+        Some(field) => match field.data_type {
+            FieldDataType::Float | FieldDataType::Integer => None,
+            _ => Some(format!(
+                "{} points to {:?}, which is not numeric",
+                name, field.name
+            )),
+        },
+    }
+}
+
+// intervals must be sorted. every interval is downsampled from the raw series, not from the previous interval, so
+// a fixed interval only has to be a multiple of the smallest one for its buckets to line up with it, e.g. 10s and 15s
+// can both be kept. calendar intervals only have an average length, so they are only checked for the order
+fn check_intervals(intervals: &[Interval]) -> Vec<String> {
+    let mut problems = vec![];
+    if intervals.is_empty() {
+        problems.push("downsampler.intervals is empty".to_owned());
+    }
+    for interval in intervals
+        .iter()
+        .filter(|interval| interval.bucket_secs == 0)
+    {
+        problems.push(format!("interval {} is empty", interval.name));
+    }
+    for pair in intervals.windows(2) {
+        let (prev, next) = (&pair[0], &pair[1]);
+        if next.bucket_secs <= prev.bucket_secs {
+            problems.push(format!(
+                "intervals aren't sorted, {} comes after {}",
+                next.name, prev.name
+            ));
+        }
+    }
+    let fixed = intervals
+        .iter()
+        .filter(|interval| matches!(interval.kind, IntervalKind::Fixed) && interval.bucket_secs > 0);
+    if let Some(smallest) = fixed.clone().min_by_key(|interval| interval.bucket_secs) {
+        for interval in fixed.filter(|interval| interval.bucket_secs % smallest.bucket_secs != 0) {
+            problems.push(format!(
+                "interval {} isn't a multiple of {}",
+                interval.name, smallest.name
+            ));
+        }
+    }
+
+    problems
+}
+
+// every query template rendered for the last day, `limit: 0` disables the limit
+fn sample_queries(config: &Config, id: &str, limit: i64) -> Vec<(&'static str, String)> {
+    let end = Utc::now().naive_utc();
+    let start = end - Duration::days(1);
+
+    vec![
+        (
+            "downsampler query",
+            downsampling::build_query(
+                &Template::new(&config.downsampler.query_template),
+                id,
+                start,
+                end,
+                limit,
+                "raw",
+            ),
+        ),
+        (
+            "listen query",
+            downsampling::build_query(
+                &Template::new(&config.listen.query_template),
+                id,
+                start,
+                end,
+                limit,
+                "raw",
+            ),
+        ),
+        (
+            "splitter query",
+            splitter::build_query(
+                &Template::new(&config.splitter.query_template),
+                id,
+                start,
+                end,
+                limit,
+            ),
+        ),
+    ]
+}
+
+// runs every query with a limit of 1 and compares the returned columns with the configured fields
+fn check_queries(config: &Config, id: &str) -> Vec<String> {
+    let client = influx_client(
+        &config.influxdb.url,
+        &config.influxdb.db,
+        &config.influxdb.username,
+        &config.influxdb.pass,
+    );
+    let fields: HashMap<&str, (&Vec<Field>, &str)> = [
+        (
+            "downsampler query",
+            (&config.downsampler.fields, &*config.downsampler.query_template),
+        ),
+        (
+            "listen query",
+            (&config.downsampler.fields, &*config.listen.query_template),
+        ),
+        (
+            "splitter query",
+            (&config.splitter.fields, &*config.splitter.query_template),
+        ),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+
+    let mut problems = vec![];
+    for (name, query) in sample_queries(config, id, 1) {
+        let (fields, template) = fields[name];
+        // without the placeholder the limit of 1 can't be applied, and the whole last day is fetched
+        if !placeholders(template).contains(&"limit") {
+            println!(
+                "Warning: {} has no {{{{limit}}}} placeholder, it ran without a limit",
+                name
+            );
+        }
+        let series = match get_range(&client, &query) {
+            Ok(series) => series,
+            Err(InfluxError::NoResult) => {
+                println!(
+                    "{} returned no data for {} in the last day, columns not checked",
+                    name, id
+                );
+                continue;
+            }
+            Err(e) => {
+                problems.push(format!("{} failed: {}", name, e));
+                continue;
+            }
+        };
+
+        if series.columns.len() != fields.len() {
+            problems.push(format!(
+                "{} returned the columns {:?}, but {} fields are configured",
+                name,
+                series.columns,
+                fields.len()
+            ));
+        }
+        // the first column is always `time`, whatever the field is called
+        for (column, field) in series.columns.iter().zip(fields.iter()).skip(1) {
+            if *column != field.name {
+                problems.push(format!(
+                    "{} returned the column {:?} where the field {:?} is configured",
+                    name, column, field.name
+                ));
+            }
+        }
+        if let Err(e) = from_json_values(&series.values, fields) {
+            problems.push(format!(
+                "{} returned a value that doesn't match its field: {}",
+                name, e
+            ));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{config_from_file, CalendarUnit};
+    use chrono_tz::Tz;
+
+    fn interval(name: &str, bucket_secs: u64) -> Interval {
+        Interval {
+            name: name.to_owned(),
+            kind: IntervalKind::Fixed,
+            bucket_secs,
+            points_per_bucket: 60,
+            offset_secs: 0,
+        }
+    }
+
+    #[test]
+    fn test_placeholders() {
+        assert_eq!(
+            placeholders("from trades_{{id}}_{{time_interval}} limit {{ limit}} {{x"),
+            vec!["id", "time_interval", " limit"]
+        );
+        assert!(placeholders("select *").is_empty());
+    }
+
+    #[test]
+    fn test_check_intervals() {
+        let intervals = [interval("1s", 1), interval("5s", 5), interval("1m", 60)];
+        assert!(check_intervals(&intervals).is_empty());
+        assert_eq!(check_intervals(&[interval("5s", 5), interval("1s", 1)]).len(), 1);
+        let intervals = [interval("5s", 5), interval("10s", 10), interval("15s", 15)];
+        assert!(check_intervals(&intervals).is_empty());
+        assert_eq!(check_intervals(&[interval("10s", 10), interval("15s", 15)]).len(), 1);
+
+        // a week isn't checked against the calendar month's average length
+        let mut week = interval("1w", 7 * 86400);
+        week.kind = IntervalKind::Calendar(CalendarUnit::Week, Tz::UTC);
+        let mut month = interval("1M", 2629746);
+        month.kind = IntervalKind::Calendar(CalendarUnit::Month, Tz::UTC);
+        assert!(check_intervals(&[interval("1d", 86400), week, month]).is_empty());
+    }
+
+    #[test]
+    fn test_default_config_intervals() {
+        let config = config_from_file("config.toml").unwrap();
+        for pipeline in config.pipelines(None).unwrap() {
+            assert_eq!(check_intervals(&pipeline.downsampler.intervals), Vec::<String>::new());
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use chrono::{NaiveDateTime, Utc};
+use crate::listen::{checkpoint_key, edit_checkpoints, get_checkpoints};
+use crate::settings::Config;
+use crate::utils::{error::print_err_and_exit, glob::glob_match};
+use humantime::format_duration;
+use redis::Connection;
+use std::collections::{BTreeMap, BTreeSet};
+use time::Duration;
+
+// selects checkpoints by id glob and interval name, `None` selects all
+pub struct CheckpointsFilter {
+    pub id: Option<String>,
+    pub interval: Option<String>,
+}
+
+pub enum CheckpointsAction {
+    List(CheckpointsFilter),
+    Set(CheckpointsFilter, NaiveDateTime),
+    Rewind(CheckpointsFilter, Duration),
+    Delete(CheckpointsFilter),
+}
+
+impl CheckpointsFilter {
+    fn matches(&self, id: &str, interval: &str) -> bool {
+        self.id
+            .as_ref()
+            .is_none_or(|pattern| glob_match(pattern, id))
+            && self.interval.as_ref().is_none_or(|name| name == interval)
+    }
+}
+
+pub fn checkpoints(action: &CheckpointsAction, config: &Config) {
+    let client = redis::Client::open(config.listen.redis_url.as_str())
+        .unwrap_or_else(|e| print_err_and_exit(e));
+    let con = client
+        .get_connection()
+        .unwrap_or_else(|e| print_err_and_exit(e));
+
+    match action {
+        CheckpointsAction::List(filter) => {
+            let now = Utc::now().naive_utc();
+            let selected = select(&con, config, filter);
+            for (_, (id, interval, ts)) in selected.iter() {
+                println!(
+                    "{:<12} {:<6} {}  lag: {}",
+                    id,
+                    interval,
+                    ts,
+                    format_lag(now.signed_duration_since(*ts))
+                );
+            }
+            println!("{} checkpoints", selected.len());
+        }
+        CheckpointsAction::Set(filter, ts) => {
+            // unlike the other actions, this also creates checkpoints for configured ids that have none yet
+            let all = CheckpointsFilter {
+                id: None,
+                interval: None,
+            };
+            let ids: BTreeSet<String> = select(&con, config, &all)
+                .into_iter()
+                .map(|(_, (id, _, _))| id)
+                .chain(config.vars.ids.iter().cloned())
+                .collect();
+
+            let mut set = vec![];
+            for id in ids.iter() {
+                for interval_period in config.downsampler.intervals.iter() {
+                    if filter.matches(id, &interval_period.name) {
+                        set.push((checkpoint_key(id, interval_period), *ts));
+                    }
+                }
+            }
+            edit_checkpoints(&con, config, &set, &[]).unwrap_or_else(|e| print_err_and_exit(e));
+            println!("Set {} checkpoints to {}", set.len(), ts);
+        }
+        CheckpointsAction::Rewind(filter, by) => {
+            let selected = select(&con, config, filter);
+            let set: Vec<(String, NaiveDateTime)> = selected
+                .iter()
+                .map(|(key, (_, _, ts))| (key.clone(), *ts - *by))
+                .collect();
+            edit_checkpoints(&con, config, &set, &[]).unwrap_or_else(|e| print_err_and_exit(e));
+            for ((key, (_, _, ts)), (_, rewound)) in selected.iter().zip(set.iter()) {
+                println!("{}: {} -> {}", key, ts, rewound);
+            }
+            println!("Rewound {} checkpoints", set.len());
+        }
+        CheckpointsAction::Delete(filter) => {
+            let delete: Vec<String> = select(&con, config, filter).into_keys().collect();
+            edit_checkpoints(&con, config, &[], &delete).unwrap_or_else(|e| print_err_and_exit(e));
+            for key in delete.iter() {
+                println!("Deleted {}", key);
+            }
+            println!("Deleted {} checkpoints", delete.len());
+        }
+    }
+}
+
+// the stored checkpoints matching the filter, as key -> (id, interval name, checkpoint)
+fn select(
+    con: &Connection,
+    config: &Config,
+    filter: &CheckpointsFilter,
+) -> BTreeMap<String, (String, String, NaiveDateTime)> {
+    get_checkpoints(con, config)
+        .unwrap_or_else(|e| print_err_and_exit(e))
+        .into_iter()
+        .filter_map(|(key, ts)| {
+            let (id, interval) = split_checkpoint_key(&key, config);
+            if filter.matches(&id, &interval) {
+                Some((key, (id, interval, ts)))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// inverse of `checkpoint_key`. keys of intervals that are no longer configured keep an empty interval.
+fn split_checkpoint_key(key: &str, config: &Config) -> (String, String) {
+    config
+        .downsampler
+        .intervals
+        .iter()
+        .filter_map(|interval_period| {
+            let suffix = format!("_{}", interval_period.name);
+            if key.ends_with(&suffix) {
+                Some((
+                    key[..key.len() - suffix.len()].to_owned(),
+                    interval_period.name.clone(),
+                ))
+            } else {
+                None
+            }
+        })
+        .next()
+        .unwrap_or_else(|| (key.to_owned(), String::new()))
+}
+
+fn format_lag(lag: Duration) -> String {
+    match lag.to_std() {
+        Ok(lag) => format_duration(std::time::Duration::from_secs(lag.as_secs())).to_string(),
+        Err(_) => format!("-{}", format_lag(-lag)),
+    }
+}
@@ -0,0 +1,80 @@
+use crate::settings::Splitter;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use time::Duration;
+
+// the chunk a query of an id starts with, until its point counts are known
+const INITIAL_CHUNK_SECS: i64 = 3600;
+// how much a chunk may grow from one query to the next, so that a quiet stretch doesn't lead to a huge query
+const MAX_GROWTH: f64 = 4.0;
+
+// sizes the time chunks `split` queries so that each returns about `target_points_per_query` points.
+// learned per id from the point counts of its earlier chunks, shared by the threads working on the same id.
+pub struct ChunkSizer {
+    target_points: usize,
+    min: Duration,
+    max: Duration,
+    sizes: Mutex<HashMap<String, Duration>>,
+}
+
+impl ChunkSizer {
+    pub fn new(splitter: &Splitter) -> ChunkSizer {
+        ChunkSizer {
+            target_points: splitter.target_points_per_query,
+            min: Duration::seconds(splitter.min_chunk_secs as i64),
+            max: Duration::seconds(splitter.work_unit_secs as i64),
+            sizes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn next(&self, id: &str) -> Duration {
+        match self.sizes.lock().unwrap().get(id) {
+            Some(size) => *size,
+            None => self.clamp(Duration::seconds(INITIAL_CHUNK_SECS)),
+        }
+    }
+
+    // a query of `id` over `chunk` returned `points`
+    pub fn observe(&self, id: &str, chunk: Duration, points: usize) {
+        let secs = chunk.num_milliseconds() as f64 / 1000.0;
+        let target_secs = match points {
+            0 => secs * MAX_GROWTH,
+            _ => (secs * self.target_points as f64 / points as f64).min(secs * MAX_GROWTH),
+        };
+        let size = self.clamp(Duration::milliseconds((target_secs * 1000.0) as i64));
+        self.sizes.lock().unwrap().insert(id.to_owned(), size);
+    }
+
+    fn clamp(&self, size: Duration) -> Duration {
+        size.max(self.min).min(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_size_adapts() {
+        let sizer = ChunkSizer {
+            target_points: 1000,
+            min: Duration::minutes(1),
+            max: Duration::days(1),
+            sizes: Mutex::new(HashMap::new()),
+        };
+        assert_eq!(sizer.next("hot"), Duration::hours(1));
+
+        // 10x the target shrinks the chunk to a tenth
+        sizer.observe("hot", Duration::hours(1), 10_000);
+        assert_eq!(sizer.next("hot"), Duration::minutes(6));
+        sizer.observe("hot", Duration::minutes(6), 1_000_000);
+        assert_eq!(sizer.next("hot"), Duration::minutes(1));
+
+        // a quiet id grows by at most `MAX_GROWTH` per query, up to the maximum
+        sizer.observe("quiet", Duration::hours(1), 1);
+        assert_eq!(sizer.next("quiet"), Duration::hours(4));
+        sizer.observe("quiet", Duration::hours(16), 0);
+        assert_eq!(sizer.next("quiet"), Duration::days(1));
+        assert_eq!(sizer.next("hot"), Duration::minutes(1));
+    }
+}
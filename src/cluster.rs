@@ -0,0 +1,468 @@
+use crate::settings::Listener;
+use crate::utils::shutdown;
+use log::{info, warn};
+use r2d2_redis::{r2d2, RedisConnectionManager};
+use redis::{Connection, RedisResult, Script};
+use std::collections::{HashMap, HashSet};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// coordinates several `listen` instances sharing one Redis. every instance heartbeats its own key,
+// ids are assigned to the live instances by rendezvous hashing, and an instance only processes
+// an id while it holds that id's lease, which keeps two instances from ever working on one id
+// while they disagree about who is alive.
+
+const INSTANCES_SET_NAME: &str = "downsampler_instances";
+const INSTANCE_KEY_PREFIX: &str = "downsampler_instance:";
+const LEASE_KEY_PREFIX: &str = "downsampler_lease:";
+
+// KEYS: lease keys, ARGV: instance id, ttl in ms. takes or renews every lease that is free or already ours
+const ACQUIRE_LEASES_SCRIPT: &str = r"
+local held = {}
+for i, key in ipairs(KEYS) do
+    local owner = redis.call('GET', key)
+    if owner == false or owner == ARGV[1] then
+        redis.call('SET', key, ARGV[1], 'PX', ARGV[2])
+        held[i] = 1
+    else
+        held[i] = 0
+    end
+end
+return held
+";
+
+// KEYS: lease keys, ARGV: instance id. deletes the leases that are still ours
+const RELEASE_LEASES_SCRIPT: &str = r"
+for _, key in ipairs(KEYS) do
+    if redis.call('GET', key) == ARGV[1] then
+        redis.call('DEL', key)
+    end
+end
+return 0
+";
+
+pub struct Cluster {
+    pub instance_id: String,
+    lease_ttl_ms: u64,
+    state: Mutex<State>,
+}
+
+struct State {
+    // live instances, including this one
+    instances: Vec<String>,
+    // ids we hold the lease of, with the acquisition that took it
+    leases: HashMap<String, u64>,
+    // counts the leases taken, so that every acquisition of an id can be told from the previous one
+    acquisitions: u64,
+    // leased ids assigned to another instance, kept until the periods in progress are written
+    draining: HashSet<String>,
+    // periods in progress per id, see `work_on`
+    busy: HashMap<String, usize>,
+}
+
+// an id being worked on, its lease isn't released before this is dropped
+pub struct Work<'a> {
+    cluster: &'a Cluster,
+    id: String,
+    acquisition: u64,
+}
+
+// the acquisition of every id a pipeline has loaded the checkpoints of. the pipelines share the leases but each
+// keeps its own checkpoints, so each one has to notice for itself that an id was acquired again after a failover:
+// another instance may have advanced its checkpoints in the meantime.
+#[derive(Default)]
+pub struct Loaded(HashMap<String, u64>);
+
+impl Loaded {
+    // whether the checkpoints of the id were loaded while we held its current lease
+    pub fn is_current(&self, work: &Work) -> bool {
+        self.0.get(&work.id) == Some(&work.acquisition)
+    }
+
+    pub fn insert(&mut self, work: &Work) {
+        self.0.insert(work.id.clone(), work.acquisition);
+    }
+}
+
+impl State {
+    fn leased(&mut self, id: &str) {
+        if !self.leases.contains_key(id) {
+            self.acquisitions += 1;
+            self.leases.insert(id.to_owned(), self.acquisitions);
+        }
+    }
+}
+
+impl Drop for Work<'_> {
+    fn drop(&mut self) {
+        let mut state = self.cluster.state.lock().unwrap();
+        if let Some(count) = state.busy.get_mut(&self.id) {
+            *count -= 1;
+            if *count == 0 {
+                state.busy.remove(&self.id);
+            }
+        }
+    }
+}
+
+impl Cluster {
+    // joins the cluster and keeps heartbeating from a background thread until shutdown
+    pub fn start(pool: &r2d2::Pool<RedisConnectionManager>, config: &Listener) -> Arc<Cluster> {
+        let instance_id = if config.instance_id.is_empty() {
+            default_instance_id()
+        } else {
+            config.instance_id.clone()
+        };
+        let cluster = Arc::new(Cluster {
+            state: Mutex::new(State {
+                instances: vec![instance_id.clone()],
+                leases: HashMap::new(),
+                acquisitions: 0,
+                draining: HashSet::new(),
+                busy: HashMap::new(),
+            }),
+            instance_id,
+            lease_ttl_ms: config.lease_ttl_ms,
+        });
+
+        info!("Joining the cluster as {}", cluster.instance_id);
+        // the first heartbeat is synchronous, so we know the other instances before taking any ids
+        if let Err(e) = pool
+            .get()
+            .map_err(|e| e.to_string())
+            .and_then(|con| cluster.heartbeat(&con).map_err(|e| e.to_string()))
+        {
+            warn!("Cluster heartbeat failed: {}", e);
+        }
+
+        let heartbeat_cluster = Arc::clone(&cluster);
+        let heartbeat_pool = pool.clone();
+        thread::spawn(move || {
+            let every = Duration::from_millis(heartbeat_cluster.lease_ttl_ms / 3);
+            while !shutdown::requested() {
+                let res = heartbeat_pool
+                    .get()
+                    .map_err(|e| e.to_string())
+                    .and_then(|con| heartbeat_cluster.heartbeat(&con).map_err(|e| e.to_string()));
+                if let Err(e) = res {
+                    warn!("Cluster heartbeat failed: {}", e);
+                }
+                shutdown::sleep(every);
+            }
+        });
+
+        cluster
+    }
+
+    // refreshes our instance key, the list of live instances and our leases.
+    // leases of ids that are now assigned to another instance are released, once no period of theirs is in progress.
+    // until then they drain: they keep their lease but no new work is started on them.
+    fn heartbeat(&self, con: &Connection) -> RedisResult<()> {
+        let instance_key = format!("{}{}", INSTANCE_KEY_PREFIX, self.instance_id);
+        redis::pipe()
+            .cmd("SET")
+            .arg(&instance_key)
+            .arg(1)
+            .arg("PX")
+            .arg(self.lease_ttl_ms)
+            .ignore()
+            .cmd("SADD")
+            .arg(INSTANCES_SET_NAME)
+            .arg(&self.instance_id)
+            .ignore()
+            .query::<()>(con)?;
+
+        let members: Vec<String> = redis::cmd("SMEMBERS").arg(INSTANCES_SET_NAME).query(con)?;
+        let mut alive_pipe = redis::pipe();
+        for member in members.iter() {
+            alive_pipe
+                .cmd("EXISTS")
+                .arg(format!("{}{}", INSTANCE_KEY_PREFIX, member));
+        }
+        let alive: Vec<bool> = alive_pipe.query(con)?;
+
+        let mut instances = vec![];
+        let mut dead = vec![];
+        for (member, alive) in members.into_iter().zip(alive) {
+            if alive {
+                instances.push(member);
+            } else {
+                dead.push(member);
+            }
+        }
+        if !dead.is_empty() {
+            info!("Instances left the cluster: {:?}", &dead);
+            redis::cmd("SREM")
+                .arg(INSTANCES_SET_NAME)
+                .arg(dead)
+                .query::<()>(con)?;
+        }
+        instances.sort();
+
+        let (keep, release) = {
+            let mut state = self.state.lock().unwrap();
+            if state.instances != instances {
+                info!("Cluster instances: {:?}", &instances);
+                state.instances = instances;
+            }
+            // decided under one lock, so that no work starts on an id we release
+            let held: Vec<String> = state.leases.keys().cloned().collect();
+            let mut keep = vec![];
+            let mut release = vec![];
+            for id in held {
+                if assigned_to(&state.instances, &id, &self.instance_id) {
+                    state.draining.remove(&id);
+                    keep.push(id);
+                } else if state.busy.contains_key(&id) {
+                    state.draining.insert(id.clone());
+                    keep.push(id);
+                } else {
+                    state.draining.remove(&id);
+                    state.leases.remove(&id);
+                    release.push(id);
+                }
+            }
+            (keep, release)
+        };
+
+        if !release.is_empty() {
+            info!("Releasing {} ids to other instances", release.len());
+            self.release(con, &release)?;
+        }
+        self.lease(con, &keep)?;
+
+        Ok(())
+    }
+
+    // whether the id is assigned to this instance, according to our view of the live instances
+    pub fn is_assigned(&self, id: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        assigned_to(&state.instances, id, &self.instance_id)
+    }
+
+    // whether we hold the lease of the id and may start work on it
+    pub fn owns(&self, id: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.leases.contains_key(id) && !state.draining.contains(id)
+    }
+
+    // the acquisitions of the ids we hold the lease of, taken before a pipeline loads all checkpoints
+    pub fn loaded(&self) -> Loaded {
+        Loaded(self.state.lock().unwrap().leases.clone())
+    }
+
+    // marks the id as being worked on until the returned `Work` is dropped, if we own it.
+    // the heartbeat doesn't hand over an id while it's worked on, so a checkpoint written under a `Work`
+    // can't overwrite the one of the next owner.
+    pub fn work_on(&self, id: &str) -> Option<Work<'_>> {
+        let mut state = self.state.lock().unwrap();
+        let acquisition = match state.leases.get(id) {
+            Some(acquisition) if !state.draining.contains(id) => *acquisition,
+            _ => return None,
+        };
+        *state.busy.entry(id.to_owned()).or_insert(0) += 1;
+
+        Some(Work {
+            cluster: self,
+            id: id.to_owned(),
+            acquisition,
+        })
+    }
+
+    // tries to lease the given ids we don't hold yet. every pipeline reloads the checkpoints of an acquired id
+    // before its next period, see `Loaded`
+    pub fn acquire(&self, con: &Connection, ids: &[String]) -> RedisResult<()> {
+        let new_ids: Vec<String> = {
+            let state = self.state.lock().unwrap();
+            ids.iter()
+                .filter(|id| !state.leases.contains_key(*id))
+                .cloned()
+                .collect()
+        };
+
+        let acquired = self.lease(con, &new_ids)?;
+        if !acquired.is_empty() {
+            info!("Acquired leases for {:?}", &acquired);
+        }
+
+        Ok(())
+    }
+
+    // leaves the cluster right away instead of waiting for our heartbeat to expire
+    pub fn leave(&self, con: &Connection) -> RedisResult<()> {
+        let held: Vec<String> = self.state.lock().unwrap().leases.keys().cloned().collect();
+        self.release(con, &held)?;
+
+        redis::pipe()
+            .cmd("DEL")
+            .arg(format!("{}{}", INSTANCE_KEY_PREFIX, self.instance_id))
+            .ignore()
+            .cmd("SREM")
+            .arg(INSTANCES_SET_NAME)
+            .arg(&self.instance_id)
+            .ignore()
+            .query(con)
+    }
+
+    // takes or renews the leases of the ids, returns the ones we hold now
+    fn lease(&self, con: &Connection, ids: &[String]) -> RedisResult<Vec<String>> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let script = Script::new(ACQUIRE_LEASES_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        for id in ids {
+            invocation.key(lease_key(id));
+        }
+        let held: Vec<i64> = invocation
+            .arg(&self.instance_id)
+            .arg(self.lease_ttl_ms)
+            .invoke(con)?;
+
+        let mut state = self.state.lock().unwrap();
+        let mut leased = vec![];
+        for (id, held) in ids.iter().zip(held) {
+            if held == 1 {
+                state.leased(id);
+                leased.push(id.clone());
+            } else {
+                state.leases.remove(id);
+                state.draining.remove(id);
+            }
+        }
+
+        Ok(leased)
+    }
+
+    fn release(&self, con: &Connection, ids: &[String]) -> RedisResult<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        {
+            let mut state = self.state.lock().unwrap();
+            for id in ids {
+                state.leases.remove(id);
+                state.draining.remove(id);
+            }
+        }
+
+        let script = Script::new(RELEASE_LEASES_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        for id in ids {
+            invocation.key(lease_key(id));
+        }
+        invocation.arg(&self.instance_id).invoke(con)
+    }
+}
+
+// the instance with the highest rendezvous weight for the id, any instance if we don't know of one
+fn assigned_to(instances: &[String], id: &str, instance_id: &str) -> bool {
+    let owner = instances
+        .iter()
+        .max_by_key(|instance| rendezvous_weight(instance, id));
+
+    owner.is_none_or(|owner| *owner == instance_id)
+}
+
+fn lease_key(id: &str) -> String {
+    format!("{}{}", LEASE_KEY_PREFIX, id)
+}
+
+pub fn default_instance_id() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "downsampler".to_owned());
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    format!("{}-{}-{}", host, process::id(), started)
+}
+
+// 64-bit FNV-1a of `instance` and `id`. stable across builds and platforms,
+// which matters because every instance must come to the same assignment.
+pub fn rendezvous_weight(instance: &str, id: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in instance.bytes().chain(Some(0u8)).chain(id.bytes()) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rendezvous_weight_is_stable() {
+        assert_eq!(rendezvous_weight("", ""), 0xaf63_bd4c_8601_b7df);
+        assert_ne!(
+            rendezvous_weight("a", "BTCUSDT"),
+            rendezvous_weight("b", "BTCUSDT")
+        );
+    }
+
+    #[test]
+    fn test_draining_id_takes_no_new_work() {
+        let cluster = Cluster {
+            instance_id: "a".to_owned(),
+            lease_ttl_ms: 1000,
+            state: Mutex::new(State {
+                instances: vec!["a".to_owned()],
+                leases: ["x", "y"].iter().map(|id| (id.to_string(), 1)).collect(),
+                acquisitions: 1,
+                draining: ["y".to_owned()].iter().cloned().collect(),
+                busy: HashMap::new(),
+            }),
+        };
+        assert!(cluster.work_on("y").is_none());
+        assert!(cluster.work_on("z").is_none());
+        assert!(!cluster.owns("y"));
+
+        let first = cluster.work_on("x").unwrap();
+        let second = cluster.work_on("x").unwrap();
+        assert_eq!(cluster.state.lock().unwrap().busy["x"], 2);
+        drop(first);
+        drop(second);
+        assert!(cluster.state.lock().unwrap().busy.is_empty());
+    }
+
+    #[test]
+    fn test_every_pipeline_sees_a_reacquired_id() {
+        let cluster = Cluster {
+            instance_id: "a".to_owned(),
+            lease_ttl_ms: 1000,
+            state: Mutex::new(State {
+                instances: vec!["a".to_owned()],
+                leases: HashMap::new(),
+                acquisitions: 0,
+                draining: HashSet::new(),
+                busy: HashMap::new(),
+            }),
+        };
+        // two jobs sharing the id "x", which the first poll of either acquires for both
+        let (mut first_job, mut second_job) = (cluster.loaded(), cluster.loaded());
+        cluster.state.lock().unwrap().leased("x");
+        for loaded in [&mut first_job, &mut second_job].iter_mut() {
+            let work = cluster.work_on("x").unwrap();
+            assert!(!loaded.is_current(&work));
+            loaded.insert(&work);
+            assert!(loaded.is_current(&work));
+        }
+
+        // renewing doesn't make it a new acquisition
+        cluster.state.lock().unwrap().leased("x");
+        assert!(first_job.is_current(&cluster.work_on("x").unwrap()));
+
+        // lost to another instance and taken back
+        cluster.state.lock().unwrap().leases.remove("x");
+        cluster.state.lock().unwrap().leased("x");
+        let work = cluster.work_on("x").unwrap();
+        assert!(!first_job.is_current(&work) && !second_job.is_current(&work));
+    }
+}
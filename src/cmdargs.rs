@@ -1,9 +1,22 @@
-use chrono::{format::ParseError, offset::TimeZone, NaiveDateTime, Utc};
+use chrono::{format::ParseError, NaiveDateTime, Timelike, Utc};
 use clap::ArgMatches;
 use clap::{crate_version, App, Arg, ArgGroup, SubCommand};
-use crate::utils::time::truncate_seconds;
+use crate::check_config::CheckConfigArgs;
+use crate::backfill::BackfillAction;
+use crate::checkpoints::{CheckpointsAction, CheckpointsFilter};
+use crate::dead_letters::DeadLettersAction;
+use crate::schedule::{Order, Shard};
+use crate::selection::Selection;
+use crate::settings::Limits;
+use crate::settings::TimestampUnit;
+use crate::utils::time::{parse_timestamp, truncate_seconds};
 use failure_derive::Fail;
 use humantime::{parse_duration as human_parse_duration, DurationError};
+use log::{debug, info};
+use regex::Regex;
+use std::fmt;
+use std::fs;
+use std::io;
 use time::{Duration, OutOfRangeError};
 
 #[derive(Fail, Debug)]
@@ -16,7 +29,7 @@ pub enum Error {
     )]
     DateParseError {
         datetime: Option<String>,
-        inner: Option<ParseError>,
+        inner: Option<DateError>,
     },
     #[fail(
         display = "Failed to parse duration from argument: {:?}, Error: {:?}",
@@ -27,15 +40,38 @@ pub enum Error {
         inner: Option<DurationError>,
     },
     #[fail(
-        display = "Invalid `start` argument passed. It should have this format: '2018-10-10 10:10:10'. Error: {:?}",
+        display = "Invalid `start` argument passed. It should be a datetime such as '2018-10-10 10:10:10' or '2018-10-10T12:10:10+02:00', an epoch timestamp, or relative like 'now-6h'. Error: {:?}",
         _0
     )]
     InvalidStartArgument(Box<Error>),
     #[fail(
-        display = "Invalid `end` argument passed. It should have this format: '2018-10-10 10:10:10'. Error: {:?}",
+        display = "Invalid `end` argument passed. It should be a datetime such as '2018-10-10 10:10:10' or '2018-10-10T12:10:10+02:00', an epoch timestamp, or relative like 'now-6h'. Error: {:?}",
         _0
     )]
     InvalidEndArgument(Box<Error>),
+    #[fail(
+        display = "Invalid `time` argument passed. It should be a datetime such as '2018-10-10 10:10:10' or '2018-10-10T12:10:10+02:00', an epoch timestamp, or relative like 'now-6h'. Error: {:?}",
+        _0
+    )]
+    InvalidTimeArgument(Box<Error>),
+    #[fail(
+        display = "Invalid `align` argument passed: {:?}. It should be one of none, second, minute, hour or day",
+        _0
+    )]
+    InvalidAlignArgument(String),
+    #[fail(display = "Invalid `sample` argument passed: {:?}. It should be a number of queries", _0)]
+    InvalidSampleArgument(String),
+    #[fail(display = "Invalid `{}` argument passed: {:?}. It should be a number, 0 is unlimited", _0, _1)]
+    InvalidLimitArgument(&'static str, String),
+    #[fail(
+        display = "Invalid `shard` argument passed: {:?}. It should be INDEX/COUNT with INDEX below COUNT e.g 0/4",
+        _0
+    )]
+    InvalidShardArgument(String),
+    #[fail(display = "Invalid `{}` regex passed. Error: {}", arg, inner)]
+    InvalidRegexArgument { arg: &'static str, inner: regex::Error },
+    #[fail(display = "Failed to read the id file {:?}. Error: {}", path, inner)]
+    InvalidIdFile { path: String, inner: io::Error },
     #[fail(
         display = "Invalid `duration` argument passed. It should have this format: '1hour 12min 5s'. Error: {:?}",
         _0
@@ -48,17 +84,132 @@ pub enum Error {
     DurationTooLong(OutOfRangeError),
 }
 
+// why a datetime argument didn't parse
+#[derive(Debug)]
+pub enum DateError {
+    // it isn't a datetime, a timestamp or relative
+    Format(ParseError),
+    // the duration after now, today or yesterday
+    Offset(DurationError),
+}
+
+impl fmt::Display for DateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DateError::Format(e) => write!(f, "{}", e),
+            DateError::Offset(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// what the start and end of a batch run are rounded down to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Align {
+    // taken as given
+    None,
+    Second,
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Align {
+    fn parse(s: &str) -> Result<Align, Error> {
+        match s {
+            "none" => Ok(Align::None),
+            "second" => Ok(Align::Second),
+            "minute" => Ok(Align::Minute),
+            "hour" => Ok(Align::Hour),
+            "day" => Ok(Align::Day),
+            _ => Err(Error::InvalidAlignArgument(s.to_owned())),
+        }
+    }
+
+    pub fn apply(self, dt: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Align::None => dt,
+            Align::Second => dt.with_nanosecond(0).unwrap(),
+            Align::Minute => truncate_seconds(dt),
+            Align::Hour => dt.date().and_hms(dt.hour(), 0, 0),
+            Align::Day => dt.date().and_hms(0, 0, 0),
+        }
+    }
+}
+
+// what a batch run does when an id fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorPolicy {
+    // the other ids carry on
+    Continue,
+    // the other ids stop at their next period, as they would when interrupted
+    FailFast,
+}
+
 pub struct TimePeriod {
     pub raw_start: NaiveDateTime,
     pub raw_end: NaiveDateTime,
     pub start: NaiveDateTime,
     pub end: NaiveDateTime,
+    // how `start` and `end` were aligned from the raw ones
+    pub align: Align,
+}
+
+// arguments shared by the batch commands, `downsample` and `split`
+pub struct BatchArgs {
+    pub period: TimePeriod,
+    // serve the metrics endpoint while running
+    pub metrics: bool,
+    pub selection: Selection,
+    // only print what would be done
+    pub dry_run: bool,
+    // how many queries a dry run actually runs
+    pub sample: usize,
+    // where to write the JSON summary of the run
+    pub report: Option<String>,
+    pub on_error: ErrorPolicy,
+    pub limits: LimitOverrides,
+    // in which order `downsample` goes through its work units, and which part of them it does
+    pub order: Order,
+    pub shard: Option<Shard>,
+}
+
+// `[limits]` of the config overridden from the command line
+pub struct LimitOverrides {
+    pub workers: Option<usize>,
+    pub max_queries_per_sec: Option<u64>,
+    pub max_points_per_sec: Option<u64>,
+}
+
+impl LimitOverrides {
+    pub fn apply(&self, limits: &mut Limits) {
+        if let Some(workers) = self.workers {
+            limits.workers = workers;
+        }
+        if let Some(max_queries_per_sec) = self.max_queries_per_sec {
+            limits.max_queries_per_sec = max_queries_per_sec;
+        }
+        if let Some(max_points_per_sec) = self.max_points_per_sec {
+            limits.max_points_per_sec = max_points_per_sec;
+        }
+    }
+}
+
+pub struct Args {
+    // path of the config file, the extension may be omitted
+    pub config_path: String,
+    // one of the `[[jobs]]` of the config
+    pub job: Option<String>,
+    pub command: CmdArgs,
 }
 
 pub enum CmdArgs {
-    Downsample(TimePeriod),
-    Split(TimePeriod),
-    Listen,
+    Downsample(BatchArgs),
+    Split(BatchArgs),
+    Listen(Selection),
+    DeadLetters(DeadLettersAction),
+    Checkpoints(CheckpointsAction),
+    CheckConfig(CheckConfigArgs),
+    Backfill(BackfillAction),
 }
 
 fn args_definitions<'a, 'b>() -> App<'a, 'b> {
@@ -66,7 +217,7 @@ fn args_definitions<'a, 'b>() -> App<'a, 'b> {
         .short("s")
         .long("start")
         .value_name("DATETIME")
-        .help("Start time e.g 2018-10-10 10:10:10")
+        .help("Start time e.g 2018-10-10 10:10:10, 2018-10-10T10:10:10Z, 1539166210 or now-6h")
         .required(true)
         .takes_value(true);
 
@@ -74,7 +225,7 @@ fn args_definitions<'a, 'b>() -> App<'a, 'b> {
         .short("e")
         .long("end")
         .value_name("DATETIME")
-        .help("End time e.g 2018-11-11 11:11:11")
+        .help("End time e.g 2018-11-11 11:11:11, 2018-11-11T11:11:11Z, 1541934671 or today")
         .takes_value(true);
 
     let duration_arg = Arg::with_name("duration")
@@ -97,6 +248,127 @@ years, year, y -- defined as 365.25 days
             "#)
         .takes_value(true);
 
+    let dead_letter_id_arg = Arg::with_name("id")
+        .long("id")
+        .value_name("ID")
+        .help("Only the dead letters of this id, all of them if omitted")
+        .takes_value(true);
+
+    let checkpoint_id_arg = Arg::with_name("id")
+        .long("id")
+        .value_name("GLOB")
+        .help("Ids to apply to, e.g. 'BTC*' or '*' for all")
+        .takes_value(true);
+
+    let checkpoint_interval_arg = Arg::with_name("interval")
+        .long("interval")
+        .value_name("NAME")
+        .help("Interval to apply to e.g 1m, all intervals if omitted")
+        .takes_value(true);
+
+    let align_arg = Arg::with_name("align")
+        .long("align")
+        .value_name("UNIT")
+        .help("Rounds the start and end down to a whole second, minute, hour or day (UTC)")
+        .possible_values(&["none", "second", "minute", "hour", "day"])
+        .default_value("none")
+        .takes_value(true);
+
+    let metrics_arg = Arg::with_name("metrics")
+        .long("metrics")
+        .help("Serves Prometheus metrics on `metrics.addr` from the config while running");
+
+    let report_arg = Arg::with_name("report")
+        .long("report")
+        .value_name("FILE")
+        .help("Writes a JSON summary of the run per id (queries, points in and out, errors, duration) to FILE")
+        .takes_value(true);
+
+    let on_error_arg = Arg::with_name("on-error")
+        .long("on-error")
+        .value_name("POLICY")
+        .help("Whether the other ids continue or stop when an id fails")
+        .possible_values(&["continue", "fail-fast"])
+        .default_value("continue")
+        .takes_value(true);
+
+    let limit_args = [
+        Arg::with_name("workers")
+            .long("workers")
+            .value_name("N")
+            .help("Ids processed in parallel, overrides `limits.workers`, 0 uses one per core")
+            .takes_value(true),
+        Arg::with_name("max-queries-per-sec")
+            .long("max-queries-per-sec")
+            .value_name("N")
+            .help("Overrides `limits.max_queries_per_sec`, 0 is unlimited")
+            .takes_value(true),
+        Arg::with_name("max-points-per-sec")
+            .long("max-points-per-sec")
+            .value_name("N")
+            .help("Overrides `limits.max_points_per_sec`, 0 is unlimited")
+            .takes_value(true),
+    ];
+
+    let schedule_args = [
+        Arg::with_name("order")
+            .long("order")
+            .value_name("ORDER")
+            .help("Downsamples id by id, or the oldest buckets of all ids first")
+            .possible_values(&["id", "time"])
+            .default_value("id")
+            .takes_value(true),
+        Arg::with_name("shard")
+            .long("shard")
+            .value_name("INDEX/COUNT")
+            .help("Only the part INDEX of COUNT disjoint parts of the work, split by id and day e.g 0/4")
+            .takes_value(true),
+    ];
+
+    let dry_run_args = [
+        Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("Prints the queries, measurements and an estimate of the work, then exits without writing anything"),
+        Arg::with_name("sample")
+            .long("sample")
+            .value_name("N")
+            .help("With --dry-run, runs the first N queries and prints the points they would write")
+            .requires("dry-run")
+            .takes_value(true),
+    ];
+
+    let selection_args = [
+        Arg::with_name("ids")
+            .long("ids")
+            .value_name("IDS")
+            .help("Comma separated ids to work on instead of `vars.ids`")
+            .use_delimiter(true)
+            .multiple(true)
+            .takes_value(true),
+        Arg::with_name("id-file")
+            .long("id-file")
+            .value_name("FILE")
+            .help("File with ids to work on instead of `vars.ids`, one per line, # starts a comment")
+            .takes_value(true),
+        Arg::with_name("include")
+            .long("include")
+            .value_name("REGEX")
+            .help("Only the ids matching this regex")
+            .takes_value(true),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .value_name("REGEX")
+            .help("Skips the ids matching this regex")
+            .takes_value(true),
+        Arg::with_name("intervals")
+            .long("intervals")
+            .value_name("NAMES")
+            .help("Comma separated intervals to work on e.g 1m,5m, all configured intervals if omitted")
+            .use_delimiter(true)
+            .multiple(true)
+            .takes_value(true),
+    ];
+
     let period_end_group = ArgGroup::with_name("period_end")
         .required(true)
         .args(&["end", "duration"]);
@@ -106,46 +378,330 @@ years, year, y -- defined as 365.25 days
         .author("Michael Ravits <michael@xlucidity.com>")
         .about("Utilities for transforming InfluxDB time series data")
         .bin_name("downsampler")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Config file, the extension may be omitted. Values can be overridden with DOWNSAMPLER_<SECTION>__<KEY> environment variables")
+                .default_value("config")
+                .global(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("job")
+                .long("job")
+                .value_name("NAME")
+                .help("The job of the config to run, needed when it has jobs. `listen` runs all of them if omitted")
+                .global(true)
+                .takes_value(true),
+        )
         .subcommand(
             SubCommand::with_name("split")
                 .about("Splits single measurement with many series into many separate measurements")
                 .arg(start_arg.clone())
                 .arg(end_arg.clone())
                 .arg(duration_arg.clone())
+                .arg(align_arg.clone())
+                .arg(metrics_arg.clone())
+                .args(&selection_args)
+                .args(&dry_run_args)
+                .arg(report_arg.clone())
+                .arg(on_error_arg.clone())
+                .args(&limit_args)
                 .group(period_end_group.clone()),
         )
         .subcommand(
             SubCommand::with_name("downsample")
                 .about("Creates downsampled series from a series")
+                .args(&schedule_args)
                 .arg(start_arg.clone())
                 .arg(end_arg.clone())
                 .arg(duration_arg.clone())
+                .arg(align_arg.clone())
+                .arg(metrics_arg.clone())
+                .args(&selection_args)
+                .args(&dry_run_args)
+                .arg(report_arg.clone())
+                .arg(on_error_arg.clone())
+                .args(&limit_args)
                 .group(period_end_group.clone()),
         )
-        .subcommand(SubCommand::with_name("listen").about("Continuous downsampling"))
+        .subcommand(
+            SubCommand::with_name("listen")
+                .about("Continuous downsampling")
+                .args(&selection_args),
+        )
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Validates the templates, fields and intervals of the config")
+                .arg(
+                    Arg::with_name("query")
+                        .long("query")
+                        .help("Also runs every query against InfluxDB with a limit of 1 and compares the columns with the fields"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dead-letters")
+                .about("Manages the periods the listener gave up on")
+                .subcommand(SubCommand::with_name("list").about("Lists the dead letters"))
+                .subcommand(
+                    SubCommand::with_name("retry")
+                        .about("Downsamples the dead letters again, removing the ones that succeed")
+                        .arg(dead_letter_id_arg.clone()),
+                )
+                .subcommand(
+                    SubCommand::with_name("discard")
+                        .about("Removes dead letters without retrying them")
+                        .arg(dead_letter_id_arg.clone()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backfill")
+                .about("Downsamples a period on any number of machines, through a job queue in Redis")
+                .subcommand(
+                    SubCommand::with_name("coordinate")
+                        .about("Enqueues the jobs of the period and follows the progress until the workers are done")
+                        .arg(start_arg.clone())
+                        .arg(end_arg.clone())
+                        .arg(duration_arg.clone())
+                        .arg(align_arg.clone())
+                        .args(&selection_args)
+                        .group(period_end_group.clone()),
+                )
+                .subcommand(
+                    SubCommand::with_name("work")
+                        .about("Claims and runs jobs until the backfill is done")
+                        .args(&limit_args),
+                )
+                .subcommand(SubCommand::with_name("status").about("Shows the progress and the failed jobs"))
+                .subcommand(SubCommand::with_name("clear").about("Removes the backfill and its jobs")),
+        )
+        .subcommand(
+            SubCommand::with_name("checkpoints")
+                .about("Inspects and fixes the listener checkpoints")
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Shows checkpoints and their lag behind now")
+                        .arg(checkpoint_id_arg.clone())
+                        .arg(checkpoint_interval_arg.clone()),
+                )
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Sets checkpoints, creating them for configured ids if needed")
+                        .arg(checkpoint_id_arg.clone().required(true))
+                        .arg(checkpoint_interval_arg.clone())
+                        .arg(
+                            Arg::with_name("time")
+                                .long("time")
+                                .value_name("DATETIME")
+                                .help("Checkpoint time e.g 2018-10-10 10:10:10 or now-1h")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("rewind")
+                        .about("Moves checkpoints back, so the listener downsamples that period again")
+                        .arg(checkpoint_id_arg.clone().required(true))
+                        .arg(checkpoint_interval_arg.clone())
+                        .arg(
+                            Arg::with_name("by")
+                                .long("by")
+                                .value_name("TIME")
+                                .help("How far back e.g '2h'")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("delete")
+                        .about("Removes checkpoints")
+                        .arg(checkpoint_id_arg.clone().required(true))
+                        .arg(checkpoint_interval_arg.clone()),
+                ),
+        )
 }
 
-pub fn parse_args() -> Result<CmdArgs, Error> {
+pub fn parse_args() -> Result<Args, Error> {
     let args = args_definitions().get_matches();
 
+    Ok(Args {
+        config_path: args.value_of("config").unwrap_or("config").to_owned(),
+        job: args.value_of("job").map(|job| job.to_owned()),
+        command: parse_command(&args)?,
+    })
+}
+
+fn parse_command(args: &ArgMatches) -> Result<CmdArgs, Error> {
     match args.subcommand() {
-        ("downsample", Some(subcommand)) => {
-            let time_period = parse_time_period(subcommand)?;
-            Ok(CmdArgs::Downsample(time_period))
+        ("downsample", Some(subcommand)) => Ok(CmdArgs::Downsample(parse_batch_args(subcommand)?)),
+        ("split", Some(subcommand)) => Ok(CmdArgs::Split(parse_batch_args(subcommand)?)),
+        ("listen", Some(subcommand)) => Ok(CmdArgs::Listen(parse_selection(subcommand)?)),
+        ("check-config", Some(subcommand)) => Ok(CmdArgs::CheckConfig(CheckConfigArgs {
+            query: subcommand.is_present("query"),
+        })),
+        ("dead-letters", Some(subcommand)) => {
+            let id = |args: &ArgMatches| args.value_of("id").map(|id| id.to_owned());
+            match subcommand.subcommand() {
+                ("list", Some(_)) => Ok(CmdArgs::DeadLetters(DeadLettersAction::List)),
+                ("retry", Some(args)) => {
+                    Ok(CmdArgs::DeadLetters(DeadLettersAction::Retry(id(args))))
+                }
+                ("discard", Some(args)) => {
+                    Ok(CmdArgs::DeadLetters(DeadLettersAction::Discard(id(args))))
+                }
+                _ => {
+                    println!("{}", subcommand.usage());
+                    Err(Error::CommandMissing)
+                }
+            }
         }
-        ("split", Some(subcommand)) => {
-            let time_period = parse_time_period(subcommand)?;
-            Ok(CmdArgs::Split(time_period))
+        ("checkpoints", Some(subcommand)) => {
+            let action = match subcommand.subcommand() {
+                ("list", Some(args)) => CheckpointsAction::List(parse_checkpoints_filter(args)),
+                ("set", Some(args)) => {
+                    let time = parse_datetime(args.value_of("time"))
+                        .map_err(|e| Error::InvalidTimeArgument(Box::new(e)))?;
+                    CheckpointsAction::Set(parse_checkpoints_filter(args), time)
+                }
+                ("rewind", Some(args)) => {
+                    let by = parse_duration(args.value_of("by"))
+                        .map_err(|e| Error::InvalidDurationArgument(Box::new(e)))?;
+                    CheckpointsAction::Rewind(parse_checkpoints_filter(args), by)
+                }
+                ("delete", Some(args)) => CheckpointsAction::Delete(parse_checkpoints_filter(args)),
+                _ => {
+                    println!("{}", subcommand.usage());
+                    return Err(Error::CommandMissing);
+                }
+            };
+            Ok(CmdArgs::Checkpoints(action))
+        }
+        ("backfill", Some(subcommand)) => {
+            let action = match subcommand.subcommand() {
+                ("coordinate", Some(args)) => {
+                    BackfillAction::Coordinate(parse_time_period(args)?, parse_selection(args)?)
+                }
+                ("work", Some(args)) => BackfillAction::Work(parse_limit_overrides(args)?),
+                ("status", Some(_)) => BackfillAction::Status,
+                ("clear", Some(_)) => BackfillAction::Clear,
+                _ => {
+                    println!("{}", subcommand.usage());
+                    return Err(Error::CommandMissing);
+                }
+            };
+            Ok(CmdArgs::Backfill(action))
         }
-        ("listen", Some(_)) => Ok(CmdArgs::Listen),
         _ => {
             args_definitions().print_help().unwrap();
-            return Err(Error::CommandMissing);
+            Err(Error::CommandMissing)
         }
     }
 }
 
+fn parse_batch_args(args: &ArgMatches) -> Result<BatchArgs, Error> {
+    Ok(BatchArgs {
+        period: parse_time_period(args)?,
+        metrics: args.is_present("metrics"),
+        selection: parse_selection(args)?,
+        dry_run: args.is_present("dry-run"),
+        sample: args
+            .value_of("sample")
+            .map(|n| n.parse().map_err(|_| Error::InvalidSampleArgument(n.to_owned())))
+            .transpose()?
+            .unwrap_or(0),
+        report: args.value_of("report").map(|path| path.to_owned()),
+        on_error: match args.value_of("on-error") {
+            Some("fail-fast") => ErrorPolicy::FailFast,
+            _ => ErrorPolicy::Continue,
+        },
+        limits: parse_limit_overrides(args)?,
+        order: match args.value_of("order") {
+            Some("time") => Order::Time,
+            _ => Order::Id,
+        },
+        shard: args.value_of("shard").map(parse_shard).transpose()?,
+    })
+}
+
+fn parse_shard(s: &str) -> Result<Shard, Error> {
+    let error = || Error::InvalidShardArgument(s.to_owned());
+    let (index, count) = s.split_once('/').ok_or_else(error)?;
+    let shard = Shard {
+        index: index.trim().parse().map_err(|_| error())?,
+        count: count.trim().parse().map_err(|_| error())?,
+    };
+    if shard.index >= shard.count {
+        return Err(error());
+    }
+
+    Ok(shard)
+}
+
+fn parse_limit_overrides(args: &ArgMatches) -> Result<LimitOverrides, Error> {
+    Ok(LimitOverrides {
+        workers: parse_limit(args, "workers")?.map(|workers| workers as usize),
+        max_queries_per_sec: parse_limit(args, "max-queries-per-sec")?,
+        max_points_per_sec: parse_limit(args, "max-points-per-sec")?,
+    })
+}
+
+fn parse_limit(args: &ArgMatches, name: &'static str) -> Result<Option<u64>, Error> {
+    args.value_of(name)
+        .map(|n| {
+            n.parse()
+                .map_err(|_| Error::InvalidLimitArgument(name, n.to_owned()))
+        })
+        .transpose()
+}
+
+fn parse_selection(args: &ArgMatches) -> Result<Selection, Error> {
+    let values = |name| {
+        args.values_of(name)
+            .map(|values| values.map(|value| value.trim().to_owned()).collect::<Vec<_>>())
+    };
+    let regex = |arg| {
+        args.value_of(arg)
+            .map(|re| Regex::new(re).map_err(|inner| Error::InvalidRegexArgument { arg, inner }))
+            .transpose()
+    };
+
+    let mut ids = values("ids");
+    if let Some(path) = args.value_of("id-file") {
+        let content = fs::read_to_string(path).map_err(|inner| Error::InvalidIdFile {
+            path: path.to_owned(),
+            inner,
+        })?;
+        ids.get_or_insert_with(Vec::new).extend(parse_id_file(&content));
+    }
+
+    Ok(Selection {
+        ids,
+        include: regex("include")?,
+        exclude: regex("exclude")?,
+        intervals: values("intervals"),
+    })
+}
+
+fn parse_id_file(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|id| !id.is_empty())
+        .map(|id| id.to_owned())
+        .collect()
+}
+
+fn parse_checkpoints_filter(args: &ArgMatches) -> CheckpointsFilter {
+    CheckpointsFilter {
+        id: args.value_of("id").map(|id| id.to_owned()),
+        interval: args.value_of("interval").map(|interval| interval.to_owned()),
+    }
+}
+
 fn parse_time_period(args: &ArgMatches) -> Result<TimePeriod, Error> {
+    let align = Align::parse(args.value_of("align").unwrap_or("none"))?;
     let raw_start = parse_datetime(args.value_of("start"))
         .map_err(|e| Error::InvalidStartArgument(Box::new(e)))?;
     let raw_end = if args.is_present("duration") {
@@ -157,32 +713,40 @@ fn parse_time_period(args: &ArgMatches) -> Result<TimePeriod, Error> {
             .map_err(|e| Error::InvalidEndArgument(Box::new(e)))?;
         datetime
     };
-    let start = truncate_seconds(raw_start);
-    let end = truncate_seconds(raw_end);
+    let start = align.apply(raw_start);
+    let end = align.apply(raw_end);
 
     Ok(TimePeriod {
         start,
         end,
         raw_start,
         raw_end,
+        align,
     })
 }
 
 pub fn print_args_info(settings: &CmdArgs) {
     if let Some(time_period) = match settings {
-        CmdArgs::Downsample(time_period) => Some(time_period),
-        CmdArgs::Split(time_period) => Some(time_period),
-        CmdArgs::Listen => None,
+        CmdArgs::Downsample(args) => Some(&args.period),
+        CmdArgs::Split(args) => Some(&args.period),
+        CmdArgs::Backfill(BackfillAction::Coordinate(period, _)) => Some(period),
+        CmdArgs::Listen(_)
+        | CmdArgs::Backfill(_)
+        | CmdArgs::DeadLetters(_)
+        | CmdArgs::Checkpoints(_)
+        | CmdArgs::CheckConfig(_) => None,
     } {
-        println!(
+        info!(
             "Period {:?} - {:?}",
             time_period.raw_start, time_period.raw_end
         );
-        println!(
-            "Period truncated {:?} - {:?}",
-            time_period.start, time_period.end
-        );
-        println!(
+        if time_period.align != Align::None {
+            info!(
+                "Period aligned to the {:?} {:?} - {:?}",
+                time_period.align, time_period.start, time_period.end
+            );
+        }
+        debug!(
             "Period in nanos {:?} - {:?}",
             time_period.start.timestamp_nanos(),
             time_period.end.timestamp_nanos()
@@ -193,30 +757,118 @@ pub fn print_args_info(settings: &CmdArgs) {
 fn parse_duration(date_string: Option<&str>) -> Result<Duration, Error> {
     let duration_str = date_string.ok_or(Error::DurationParseError {
         inner: None,
-        duration: date_string.map_or(None, |s| Some(s.to_string())),
+        duration: date_string.map(|s| s.to_string()),
     })?;
 
     let duration_std =
         human_parse_duration(duration_str).map_err(|e| Error::DurationParseError {
             inner: Some(e),
-            duration: date_string.map_or(None, |s| Some(s.to_string())),
+            duration: date_string.map(|s| s.to_string()),
         })?;
 
-    Duration::from_std(duration_std).map_err(|e| Error::DurationTooLong(e))
+    Duration::from_std(duration_std).map_err(Error::DurationTooLong)
 }
 
 fn parse_datetime(date_string: Option<&str>) -> Result<NaiveDateTime, Error> {
-    let date_str = date_string.ok_or(Error::DateParseError {
-        inner: None,
-        datetime: date_string.map_or(None, |s| Some(s.to_string())),
-    })?;
+    parse_datetime_at(date_string, Utc::now().naive_utc())
+}
 
-    let date_time = Utc
-        .datetime_from_str(date_str, "%Y-%m-%d %H:%M:%S")
-        .map_err(|e| Error::DateParseError {
-            inner: Some(e),
-            datetime: date_string.map_or(None, |s| Some(s.to_string())),
-        })?;
+// parses '2018-10-10 10:10:10' (UTC), RFC3339, epoch timestamps in s, ms, us or ns,
+// and now, today or yesterday +/- a duration, relative to `now`
+fn parse_datetime_at(date_string: Option<&str>, now: NaiveDateTime) -> Result<NaiveDateTime, Error> {
+    let error = |inner| Error::DateParseError {
+        inner,
+        datetime: date_string.map(|s| s.to_string()),
+    };
+    let date_str = date_string.ok_or_else(|| error(None))?.trim();
+
+    let format_error = match NaiveDateTime::parse_from_str(date_str, "%Y-%m-%d %H:%M:%S") {
+        Ok(date_time) => return Ok(date_time),
+        Err(e) => e,
+    };
+    if let Ok(date_time) = parse_timestamp(date_str, TimestampUnit::Auto) {
+        return Ok(date_time);
+    }
+
+    // now, today or yesterday, optionally followed by +/- a duration
+    let split = date_str.find(['+', '-']).unwrap_or(date_str.len());
+    let today = now.date().and_hms(0, 0, 0);
+    let anchor = match date_str[..split].trim() {
+        "now" => now,
+        "today" => today,
+        "yesterday" => today - Duration::days(1),
+        _ => return Err(error(Some(DateError::Format(format_error)))),
+    };
+    let offset = date_str[split..].trim();
+    if offset.is_empty() {
+        return Ok(anchor);
+    }
+    let duration = human_parse_duration(offset[1..].trim())
+        .map_err(|e| error(Some(DateError::Offset(e))))
+        .and_then(|d| Duration::from_std(d).map_err(|_| error(None)))?;
 
-    Ok(date_time.naive_utc())
+    Ok(if offset.starts_with('-') {
+        anchor - duration
+    } else {
+        anchor + duration
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_parse_datetime_formats() {
+        let now = utc("2018-10-10 10:10:10");
+        let parse = |s| parse_datetime_at(Some(s), now).unwrap();
+
+        assert_eq!(parse("2018-10-10 10:10:10"), now);
+        assert_eq!(parse("2018-10-10T12:10:10+02:00"), now);
+        assert_eq!(parse("1539166210"), now);
+        assert_eq!(parse("1539166210000"), now);
+        assert_eq!(parse("1539166210000000000"), now);
+        assert_eq!(parse("now"), now);
+        assert_eq!(parse("now-6h"), utc("2018-10-10 04:10:10"));
+        assert_eq!(parse("today"), utc("2018-10-10 00:00:00"));
+        assert_eq!(parse("yesterday + 1h 30min"), utc("2018-10-09 01:30:00"));
+        assert!(matches!(
+            parse_datetime_at(Some("tomorrow"), now),
+            Err(Error::DateParseError { inner: Some(DateError::Format(_)), .. })
+        ));
+        assert!(matches!(
+            parse_datetime_at(Some("now-6x"), now),
+            Err(Error::DateParseError { inner: Some(DateError::Offset(_)), .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_id_file() {
+        assert_eq!(
+            parse_id_file("binance_ETHBTC\n\n# delisted\n  binance_BTCUSDT # spot\n"),
+            vec!["binance_ETHBTC", "binance_BTCUSDT"]
+        );
+    }
+
+    #[test]
+    fn test_parse_shard() {
+        assert_eq!(parse_shard("1/4").unwrap(), Shard { index: 1, count: 4 });
+        assert!(parse_shard("4/4").is_err());
+        assert!(parse_shard("0/0").is_err());
+        assert!(parse_shard("1").is_err());
+    }
+
+    #[test]
+    fn test_align() {
+        let time = utc("2018-10-10 10:10:10");
+        assert_eq!(Align::None.apply(time), time);
+        assert_eq!(Align::Second.apply(time), time);
+        assert_eq!(Align::Minute.apply(time), utc("2018-10-10 10:10:00"));
+        assert_eq!(Align::Hour.apply(time), utc("2018-10-10 10:00:00"));
+        assert_eq!(Align::Day.apply(time), utc("2018-10-10 00:00:00"));
+    }
 }
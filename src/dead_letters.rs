@@ -0,0 +1,173 @@
+use chrono::{NaiveDateTime, Utc};
+use crate::downsampling::downsample_period;
+use crate::influx::influx_client;
+use crate::listen::render_measurement_name;
+use crate::settings::Config;
+use crate::utils::{error::print_err_and_exit, time::from_timestamp_nanos};
+use log::{info, warn};
+use redis::{Commands, Connection, RedisResult};
+use string_template::Template;
+
+// periods the listener gave up on after `listen.max_attempts`, so one bad pair doesn't block the pipeline
+const DEAD_LETTERS_LIST_NAME: &str = "downsampler_dead_letters";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: String,
+    pub interval: String,
+    // period bounds in nanoseconds
+    pub start: i64,
+    pub end: i64,
+    pub error: String,
+    pub attempts: u32,
+    pub failed_at: String,
+}
+
+impl DeadLetter {
+    pub fn new(
+        id: &str,
+        interval: &str,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+        error: String,
+        attempts: u32,
+    ) -> DeadLetter {
+        DeadLetter {
+            id: id.to_owned(),
+            interval: interval.to_owned(),
+            start: start.timestamp_nanos(),
+            end: end.timestamp_nanos(),
+            error,
+            attempts,
+            failed_at: Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+pub enum DeadLettersAction {
+    List,
+    Retry(Option<String>),
+    Discard(Option<String>),
+}
+
+pub fn push_dead_letter(con: &Connection, config: &Config, dead_letter: &DeadLetter) -> RedisResult<()> {
+    let json = serde_json::to_string(dead_letter).unwrap();
+    con.rpush(config.redis_key(DEAD_LETTERS_LIST_NAME), json)
+}
+
+// returns the raw entries along with the parsed ones, the raw ones are needed to remove them
+fn get_dead_letters(con: &Connection, config: &Config) -> RedisResult<Vec<(String, DeadLetter)>> {
+    let entries: Vec<String> = con.lrange(config.redis_key(DEAD_LETTERS_LIST_NAME), 0, -1)?;
+
+    Ok(entries
+        .into_iter()
+        .filter_map(|raw| match serde_json::from_str(&raw) {
+            Ok(dead_letter) => Some((raw, dead_letter)),
+            Err(e) => {
+                warn!("Skipping malformed dead letter {:?}: {}", raw, e);
+                None
+            }
+        })
+        .collect())
+}
+
+fn remove_dead_letter(con: &Connection, config: &Config, raw: &str) -> RedisResult<()> {
+    con.lrem(config.redis_key(DEAD_LETTERS_LIST_NAME), 1, raw)
+}
+
+pub fn dead_letters(action: &DeadLettersAction, config: &Config) {
+    let client = redis::Client::open(config.listen.redis_url.as_str())
+        .unwrap_or_else(|e| print_err_and_exit(e));
+    let con = client
+        .get_connection()
+        .unwrap_or_else(|e| print_err_and_exit(e));
+    let entries = get_dead_letters(&con, config).unwrap_or_else(|e| print_err_and_exit(e));
+
+    match action {
+        DeadLettersAction::List => {
+            for (_, dead_letter) in entries.iter() {
+                println!(
+                    "{} {} [{} - {}] attempts: {}, failed at: {}, error: {}",
+                    dead_letter.id,
+                    dead_letter.interval,
+                    from_timestamp_nanos(dead_letter.start),
+                    from_timestamp_nanos(dead_letter.end),
+                    dead_letter.attempts,
+                    dead_letter.failed_at,
+                    dead_letter.error
+                );
+            }
+            println!("{} dead letters", entries.len());
+        }
+        DeadLettersAction::Retry(id) => {
+            let influx_client = influx_client(
+                &config.influxdb.url,
+                &config.influxdb.db,
+                &config.influxdb.username,
+                &config.influxdb.pass,
+            );
+            let query_template = Template::new(&config.listen.query_template);
+            let measurement_template = Template::new(&config.listen.measurement_template);
+
+            for (raw, dead_letter) in matching(&entries, id) {
+                let interval_period = match config
+                    .downsampler
+                    .intervals
+                    .iter()
+                    .find(|interval| interval.name == dead_letter.interval)
+                {
+                    Some(interval_period) => interval_period,
+                    None => {
+                        warn!(id = dead_letter.id.as_str(), interval = dead_letter.interval.as_str();
+                              "Skipping, the interval is no longer configured");
+                        continue;
+                    }
+                };
+                let measurement_name = render_measurement_name(
+                    &dead_letter.id,
+                    &measurement_template,
+                    &dead_letter.interval,
+                );
+                let end = from_timestamp_nanos(dead_letter.end);
+
+                match downsample_period(
+                    config,
+                    &influx_client,
+                    &query_template,
+                    &dead_letter.id,
+                    end,
+                    interval_period,
+                    &measurement_name,
+                ) {
+                    Ok(_) => {
+                        remove_dead_letter(&con, config, raw).unwrap_or_else(|e| print_err_and_exit(e));
+                        info!(id = dead_letter.id.as_str(), interval = dead_letter.interval.as_str(), period:% = end;
+                              "Retried");
+                    }
+                    Err(e) => warn!(
+                        id = dead_letter.id.as_str(), interval = dead_letter.interval.as_str(), period:% = end;
+                        "Retry failed: {}", e
+                    ),
+                }
+            }
+        }
+        DeadLettersAction::Discard(id) => {
+            let mut discarded = 0;
+            for (raw, _) in matching(&entries, id) {
+                remove_dead_letter(&con, config, raw).unwrap_or_else(|e| print_err_and_exit(e));
+                discarded += 1;
+            }
+            println!("Discarded {} dead letters", discarded);
+        }
+    }
+}
+
+// all entries, or only those of the given id
+fn matching<'a>(
+    entries: &'a [(String, DeadLetter)],
+    id: &'a Option<String>,
+) -> impl Iterator<Item = &'a (String, DeadLetter)> {
+    entries
+        .iter()
+        .filter(move |(_, dead_letter)| id.as_ref().is_none_or(|id| *id == dead_letter.id))
+}
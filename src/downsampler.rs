@@ -1,16 +1,17 @@
-use crate::cmdargs::TimePeriod;
-use crate::downsampling::is_downsampling_interval;
+use crate::cmdargs::BatchArgs;
+use crate::progress::{Outcome, Progress};
+use crate::schedule::{count_units, schedule};
+use crate::utils::error::{Context, Error};
 use crate::{
     downsampling::downsample_period, influx::influx_client, settings::Config,
-    utils::time::intervals,
+    utils::shutdown,
 };
 use rayon::prelude::*;
 use std::collections::HashMap;
 use string_template::Template;
-use time::Duration;
 
-pub fn pre_render_names(config: &Config, template: Template) -> HashMap<(u64, &str), String> {
-    let mut map: HashMap<(u64, &str), String> =
+pub fn pre_render_names(config: &Config, template: Template) -> HashMap<(&str, &str), String> {
+    let mut map: HashMap<(&str, &str), String> =
         HashMap::with_capacity(config.vars.ids.len() * config.downsampler.intervals.len());
 
     for id in &config.vars.ids {
@@ -19,14 +20,14 @@ pub fn pre_render_names(config: &Config, template: Template) -> HashMap<(u64, &s
             m.insert("id", id.as_str());
             m.insert("time_interval", interval.name.as_str());
             let name = template.render(&m);
-            map.insert((interval.duration_secs, id.as_str()), name);
+            map.insert((interval.name.as_str(), id.as_str()), name);
         }
     }
 
     map
 }
 
-pub fn downsample(args: &TimePeriod, config: &Config) -> () {
+pub fn downsample(args: &BatchArgs, config: &Config) -> Outcome {
     let client = influx_client(
         &config.influxdb.url,
         &config.influxdb.db,
@@ -36,32 +37,68 @@ pub fn downsample(args: &TimePeriod, config: &Config) -> () {
 
     let measurement_template = Template::new(&config.downsampler.measurement_template);
     let query_template = Template::new(&config.downsampler.query_template);
-    let measurements = pre_render_names(&config, measurement_template);
+    let measurements = pre_render_names(config, measurement_template);
+    let progress = Progress::new("downsample", &config.vars.ids, &args.period);
 
-    //    Hey look, par_iter() !!
-    config.vars.ids.par_iter().for_each(|id| {
-        println!("start {}", id);
-
-        for (start, _end) in intervals(args.start, args.end, Duration::seconds(1)) {
-            for interval_period in config.downsampler.intervals.iter() {
-                if is_downsampling_interval(&start, interval_period) {
-                    let measurement_name = measurements
-                        .get(&(interval_period.duration_secs, id))
-                        .unwrap();
+    let (start, end) = (args.period.start, args.period.end);
+    for id in config.vars.ids.iter() {
+        let units = count_units(id, &config.downsampler.intervals, start, end, args.shard);
+        progress.expect_units(id, units);
+    }
+    let units = schedule(
+        &config.vars.ids,
+        &config.downsampler.intervals,
+        start,
+        end,
+        args.order,
+        args.shard,
+    );
 
-                    downsample_period(
-                        config,
-                        &client,
-                        &query_template,
-                        id,
-                        start,
-                        interval_period.duration_secs,
-                        measurement_name,
-                    );
+    //    Hey look, par_bridge() !! the units are generated as the threads take them, roughly in order
+    progress.run(|| {
+        units
+            .take_while(|_| !progress.stopping())
+            .par_bridge()
+            .for_each(|unit| {
+                // the other units of a failed id are skipped
+                if progress.has_failed(unit.id) {
+                    return;
                 }
-            }
-        }
+                if progress.stopping() {
+                    progress.skip_unit(unit.id, unit.end);
+                    return;
+                }
+                progress.start_id(unit.id);
+                let measurement_name = &measurements[&(unit.interval_period.name.as_str(), unit.id)];
 
-        println!("end {}", id);
+                match downsample_period(
+                    config,
+                    &client,
+                    &query_template,
+                    unit.id,
+                    unit.end,
+                    unit.interval_period,
+                    measurement_name,
+                ) {
+                    Ok((points_in, points_out)) => {
+                        progress.record_query(unit.id, points_in, points_out);
+                        progress.finish_unit(unit.id, unit.end);
+                    }
+                    Err(e) => {
+                        let context = Context::new(unit.id, Some(&unit.interval_period.name), unit.end);
+                        progress.record_failure(unit.id, &Error::influx(context, e), args.on_error);
+                    }
+                }
+            })
     });
+
+    let outcome = progress.finish(args.report.as_deref());
+    // a run starting at the end of the first bucket that wasn't downsampled redoes that bucket
+    let stopped = progress.stopped_at();
+    let interrupted = progress.resume_points(stopped.iter().map(|(id, at)| (id.as_str(), *at)));
+    if !interrupted.is_empty() {
+        shutdown::print_progress(&interrupted);
+    }
+
+    outcome
 }
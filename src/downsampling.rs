@@ -4,57 +4,78 @@ use crate::{
     influx::{
         extract_float_value, from_json_values, get_range, save_points, to_point, Error, FieldValue,
     },
+    last::last_downsample,
     lttb::{lttb_downsample, DataPoint},
-    settings::{Config, Field},
-    utils::{error::print_err_and_exit, time::UNIX_EPOCH},
+    metrics,
+    settings::{Algorithm, Config, Field},
+    utils::time::interval_start,
 };
 use influx_db_client::Client;
+use log::error;
 use influx_db_client::Point;
 use std::collections::HashMap;
 use std::ops::Sub;
-use std::time::Duration as StdDuration;
 use string_template::Template;
 use time::Duration;
 
+// returns the number of points read and written
 pub fn downsample_period(
     config: &Config,
     client: &Client,
     query_template: &Template,
     id: &str,
     end: NaiveDateTime,
-    interval_duration_secs: u64,
+    interval_period: &Interval,
     measurement_name: &str,
-) {
+) -> Result<(usize, usize), Error> {
     // TODO: batch small periods queries into large ones/load larger chunks
-    let duration = Duration::from_std(StdDuration::from_secs(interval_duration_secs)).unwrap();
-    let begin = end.sub(duration);
+    let begin = interval_start(&end.sub(Duration::nanoseconds(1)), interval_period);
 
     // TODO: attempt to downsample from downsampled series instead of from 'raw'
-    let query_str = build_query(&query_template, id, begin, end, 0, "raw");
-    let series = match get_range(&client, &query_str) {
+    let query_str = build_query(query_template, id, begin, end, 0, "raw");
+    let series = match get_range(client, &query_str) {
         Ok(series) => series,
-        Err(err) => match err {
-            Error::NoResult => return,
-            e => print_err_and_exit(e),
-        },
+        Err(Error::NoResult) => return Ok((0, 0)),
+        Err(e) => return Err(e),
     };
     let vals = from_json_values(&series.values, &config.downsampler.fields)
-        .unwrap_or_else(|e| {
-            println!("\n\nseries.values: {:#?}", &series.values);
-            print_err_and_exit(e);
-        });
+        .inspect_err(|_| error!(id = id; "series.values: {:?}", &series.values))?;
+    let labels = [config.job_label(), id, interval_period.name.as_str(), "downsample"];
+    metrics::POINTS_READ
+        .with_label_values(&labels)
+        .inc_by(vals.len() as u64);
     //                let _count = vals.iter().count();
     //                println!("{} - [{} - {}] ({})", i, start, end, _count);
-    let subset = lttb_downsample(
-        &vals,
-        60,
-        config.downsampler.x_field_index,
-        config.downsampler.y_field_index,
-    );
+    let subset = pick_points(config, &vals, interval_period);
     let points = to_influx_points(measurement_name, &vals, &subset, &config.downsampler.fields);
     //                println!("{:#?}", &points);
-    // TODO: handle errors
-    save_points(&client, &config.influxdb.retention_policy, points).unwrap();
+    let count = points.len();
+    save_points(client, &config.influxdb.retention_policy, points)
+        .map_err(Error::InfluxDbAccessError)?;
+    metrics::POINTS_WRITTEN.with_label_values(&labels).inc_by(count as u64);
+
+    Ok((vals.len(), count))
+}
+
+// the points of a bucket that are kept, with `downsampler.algorithm`. `None` keeps all of them
+pub fn pick_points<'a>(
+    config: &Config,
+    vals: &'a [Vec<FieldValue>],
+    interval_period: &Interval,
+) -> Option<Vec<&'a Vec<FieldValue>>> {
+    match config.downsampler.algorithm {
+        Algorithm::Lttb => lttb_downsample(
+            vals,
+            interval_period.points_per_bucket,
+            config.downsampler.x_field_index,
+            config.downsampler.y_field_index,
+        ),
+        Algorithm::Last => last_downsample(
+            vals,
+            interval_period.points_per_bucket,
+            config.downsampler.x_field_index,
+        ),
+    }
 }
 
 pub fn to_influx_points(
@@ -110,7 +131,27 @@ impl DataPoint for Vec<FieldValue> {
     }
 }
 
-pub fn is_downsampling_interval(start: &NaiveDateTime, interval_period: &Interval) -> bool {
-    let secs = start.signed_duration_since(*UNIX_EPOCH).num_seconds();
-    secs % (interval_period.duration_secs as i64) == 0
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::IntervalKind;
+
+    #[test]
+    fn test_interval_start_with_offset() {
+        let interval_period = Interval {
+            name: "1h".to_owned(),
+            kind: IntervalKind::Fixed,
+            bucket_secs: 3600,
+            points_per_bucket: 60,
+            offset_secs: 900,
+        };
+        let time = NaiveDateTime::from_timestamp(3600 * 5 + 600, 0);
+
+        assert_eq!(
+            interval_start(&time, &interval_period),
+            NaiveDateTime::from_timestamp(3600 * 4 + 900, 0)
+        );
+        let boundary = NaiveDateTime::from_timestamp(3600 * 4 + 900, 0);
+        assert_eq!(interval_start(&boundary, &interval_period), boundary);
+    }
 }
@@ -0,0 +1,206 @@
+use chrono::NaiveDateTime;
+use crate::chunk_size::ChunkSizer;
+use crate::cmdargs::BatchArgs;
+use crate::downsampler::pre_render_names;
+use crate::downsampling::{build_query, pick_points, to_influx_points};
+use crate::influx::{from_json_values, get_range, influx_client, Error, FieldValue};
+use crate::schedule::{count_units, schedule};
+use crate::settings::{Config, Field, Interval};
+use crate::splitter::{self, make_measurement_name, split_units, to_points};
+use crate::utils::error::print_err_and_exit;
+use crate::utils::shutdown;
+use influx_db_client::Point;
+use log::debug;
+use std::slice;
+use string_template::Template;
+use time::Duration;
+
+// `--dry-run` prints what a batch run would query and write, without writing anything.
+// with `--sample N` the first N queries are actually run and the points they would write are printed.
+
+// a query picked to be run by `--sample`
+struct Sample<'a> {
+    id: &'a str,
+    interval_period: Option<&'a Interval>,
+    measurement_name: String,
+    query: String,
+}
+
+pub fn dry_run_downsample(args: &BatchArgs, config: &Config) {
+    let query_template = Template::new(&config.downsampler.query_template);
+    let measurements = pre_render_names(
+        config,
+        Template::new(&config.downsampler.measurement_template),
+    );
+    let (start, end) = (args.period.start, args.period.end);
+
+    println!("Downsampling {} - {}", start, end);
+    let (mut queries, mut max_points) = (0, 0);
+    for interval_period in config.downsampler.intervals.iter() {
+        let chunks: u64 = config
+            .vars
+            .ids
+            .iter()
+            .map(|id| count_units(id, slice::from_ref(interval_period), start, end, args.shard))
+            .sum();
+        let points = chunks * interval_period.points_per_bucket as u64;
+        println!("  {}: {} chunks, up to {} points", interval_period.name, chunks, points);
+        queries += chunks;
+        max_points += points;
+    }
+
+    // the same work units `downsample` runs, in its order
+    let units = schedule(
+        &config.vars.ids,
+        &config.downsampler.intervals,
+        start,
+        end,
+        args.order,
+        args.shard,
+    );
+    let mut samples = vec![];
+    for unit in units {
+        if shutdown::requested() {
+            break;
+        }
+        let measurement_name = &measurements[&(unit.interval_period.name.as_str(), unit.id)];
+        let query = build_query(&query_template, unit.id, unit.start, unit.end, 0, "raw");
+        println!("{} {} -> {}: {} - {}", unit.id, unit.interval_period.name, measurement_name, unit.start, unit.end);
+        debug!(id = unit.id, interval = unit.interval_period.name.as_str(); "{}", query.trim());
+        if samples.len() < args.sample {
+            samples.push(Sample {
+                id: unit.id,
+                interval_period: Some(unit.interval_period),
+                measurement_name: measurement_name.clone(),
+                query,
+            });
+        }
+    }
+
+    println!(
+        "{} ids, {} queries, up to {} points would be written",
+        config.vars.ids.len(),
+        queries,
+        max_points
+    );
+
+    run_samples(config, &samples, queries as usize, &config.downsampler.fields, |sample, vals| {
+        let subset = pick_points(config, vals, sample.interval_period.unwrap());
+        to_influx_points(&sample.measurement_name, vals, &subset, &config.downsampler.fields)
+    });
+}
+
+pub fn dry_run_split(args: &BatchArgs, config: &Config) {
+    let query_template = Template::new(&config.splitter.query_template);
+    let measurement_template = Template::new(&config.splitter.measurement_template);
+    // the same units `split` runs, queried in chunks of the size they start with
+    let sizer = ChunkSizer::new(&config.splitter);
+    let units = split_units(
+        &config.vars.ids,
+        args.period.start,
+        args.period.end,
+        Duration::seconds(config.splitter.work_unit_secs as i64),
+    );
+
+    println!(
+        "Splitting {} - {} in {} units, queried in chunks of {} minutes at first, resized towards {} points per query",
+        args.period.start,
+        args.period.end,
+        units.len(),
+        sizer.next("").num_minutes(),
+        config.splitter.target_points_per_query
+    );
+
+    let mut samples = vec![];
+    let mut queries = 0;
+    for unit in units.iter() {
+        let measurement_name = make_measurement_name(&measurement_template, unit.id);
+        println!("{} -> {}: {} - {}", unit.id, measurement_name, unit.start, unit.end);
+
+        for (start, end) in chunks(unit.start, unit.end, sizer.next(unit.id)) {
+            let query = splitter::build_query(&query_template, unit.id, start, end, 0);
+            debug!(id = unit.id; "{}", query.trim());
+            queries += 1;
+            if samples.len() < args.sample {
+                samples.push(Sample {
+                    id: unit.id,
+                    interval_period: None,
+                    measurement_name: measurement_name.clone(),
+                    query,
+                });
+            }
+        }
+    }
+
+    println!(
+        "{} ids, {} queries at the initial chunk size, every point read would be written",
+        config.vars.ids.len(),
+        queries
+    );
+
+    run_samples(config, &samples, queries, &config.splitter.fields, |sample, vals| {
+        to_points(vals, &sample.measurement_name, &config.splitter.fields)
+    });
+}
+
+// [start, end) in chunks of `size`, the last one ending at `end`
+fn chunks(start: NaiveDateTime, end: NaiveDateTime, size: Duration) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+    let mut chunks = vec![];
+    let mut chunk_start = start;
+    while chunk_start < end {
+        let chunk_end = (chunk_start + size).min(end);
+        chunks.push((chunk_start, chunk_end));
+        chunk_start = chunk_end;
+    }
+
+    chunks
+}
+
+// runs the sampled queries and prints the points they would write, then extrapolates the total
+fn run_samples<F>(config: &Config, samples: &[Sample], queries: usize, fields: &[Field], make_points: F)
+where
+    F: Fn(&Sample, &[Vec<FieldValue>]) -> Vec<Point>,
+{
+    if samples.is_empty() {
+        return;
+    }
+    let client = influx_client(
+        &config.influxdb.url,
+        &config.influxdb.db,
+        &config.influxdb.username,
+        &config.influxdb.pass,
+    );
+
+    let mut points_written = 0;
+    for sample in samples.iter() {
+        let label = match sample.interval_period {
+            Some(interval_period) => format!("{} {}", sample.id, interval_period.name),
+            None => sample.id.to_owned(),
+        };
+        println!("Sample {}: {}", label, sample.query.trim());
+
+        let series = match get_range(&client, &sample.query) {
+            Ok(series) => series,
+            Err(Error::NoResult) => {
+                println!("  no data");
+                continue;
+            }
+            Err(e) => print_err_and_exit(e),
+        };
+        let vals = from_json_values(&series.values, fields).unwrap_or_else(|e| print_err_and_exit(e));
+        let points = make_points(sample, &vals);
+        println!("  {} points read, {} would be written:", vals.len(), points.len());
+        for point in points.iter() {
+            println!("  {:?}", point);
+        }
+        points_written += points.len();
+    }
+
+    println!(
+        "Sampled {} queries, {} points would be written, about {} for all {} queries",
+        samples.len(),
+        points_written,
+        points_written * queries / samples.len(),
+        queries
+    );
+}
@@ -0,0 +1,164 @@
+use influx_db_client::Client;
+use lazy_static::lazy_static;
+use r2d2_redis::{r2d2, RedisConnectionManager};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// what `/healthz` and `/readyz` report. the listener registers its probes and records the progress of each of
+// its pipelines, batch runs don't register anything, so the endpoints don't exist for them.
+
+lazy_static! {
+    static ref PROBES: Mutex<Option<Probes>> = Mutex::new(None);
+    static ref PROGRESS: Mutex<Progress> = Mutex::new(Progress {
+        pipelines: HashMap::new(),
+        lags: HashMap::new(),
+    });
+}
+
+pub struct Probes {
+    pub pool: r2d2::Pool<RedisConnectionManager>,
+    pub influx_client: Client,
+    // 0 disables the check
+    pub max_idle_secs: u64,
+    pub max_checkpoint_lag_secs: u64,
+}
+
+struct Progress {
+    // per job, "" for a config without jobs
+    pipelines: HashMap<String, PipelineProgress>,
+    // lag per checkpoint key of the job, in seconds
+    lags: HashMap<String, i64>,
+}
+
+struct PipelineProgress {
+    // the last successful `process_period`, or the last time there was nothing left to process
+    last_progress: Instant,
+    // the last successful `process_period`
+    last_processed: Option<Instant>,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub healthy: bool,
+    pub problems: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redis: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub influxdb: Option<bool>,
+    pub secs_since_last_processed: Option<u64>,
+    pub max_checkpoint_lag_secs: i64,
+}
+
+// `jobs` are the pipelines the listener runs, each has to make progress on its own
+pub fn register(probes: Probes, jobs: &[&str]) {
+    *PROBES.lock().unwrap() = Some(probes);
+    let mut progress = PROGRESS.lock().unwrap();
+    for job in jobs {
+        progress.pipelines.insert(
+            job.to_string(),
+            PipelineProgress {
+                last_progress: Instant::now(),
+                last_processed: None,
+            },
+        );
+    }
+}
+
+pub fn record_processed(job: &str) {
+    update(job, |pipeline| {
+        pipeline.last_progress = Instant::now();
+        pipeline.last_processed = Some(pipeline.last_progress);
+    });
+}
+
+// an idle pipeline isn't stuck
+pub fn record_idle(job: &str) {
+    update(job, |pipeline| pipeline.last_progress = Instant::now());
+}
+
+fn update<F: FnOnce(&mut PipelineProgress)>(job: &str, f: F) {
+    let mut progress = PROGRESS.lock().unwrap();
+    let pipeline = progress
+        .pipelines
+        .entry(job.to_owned())
+        .or_insert_with(|| PipelineProgress {
+            last_progress: Instant::now(),
+            last_processed: None,
+        });
+    f(pipeline);
+}
+
+pub fn record_lag(key: &str, lag_secs: i64) {
+    PROGRESS
+        .lock()
+        .unwrap()
+        .lags
+        .insert(key.to_owned(), lag_secs);
+}
+
+// liveness: the listener makes progress and keeps up with the updates.
+// readiness additionally requires Redis and InfluxDB to be reachable.
+pub fn check(readiness: bool) -> Option<Report> {
+    let probes = PROBES.lock().unwrap();
+    let probes = probes.as_ref()?;
+    let mut problems = vec![];
+
+    let (idle, secs_since_last_processed, max_lag) = {
+        let progress = PROGRESS.lock().unwrap();
+        let mut idle: Vec<(String, u64)> = progress
+            .pipelines
+            .iter()
+            .map(|(job, pipeline)| (job.clone(), pipeline.last_progress.elapsed().as_secs()))
+            .collect();
+        idle.sort();
+        (
+            idle,
+            // of the pipeline that processed a period the longest ago
+            progress
+                .pipelines
+                .values()
+                .filter_map(|pipeline| pipeline.last_processed.map(|ts| ts.elapsed().as_secs()))
+                .max(),
+            progress.lags.values().cloned().max().unwrap_or(0),
+        )
+    };
+    for (job, idle_secs) in idle {
+        if probes.max_idle_secs > 0 && idle_secs > probes.max_idle_secs {
+            match job.as_str() {
+                "" => problems.push(format!("no progress for {}s", idle_secs)),
+                job => problems.push(format!("no progress for {}s in job {}", idle_secs, job)),
+            }
+        }
+    }
+    if probes.max_checkpoint_lag_secs > 0 && max_lag > probes.max_checkpoint_lag_secs as i64 {
+        problems.push(format!("checkpoint lag of {}s", max_lag));
+    }
+
+    let (redis, influxdb) = if readiness {
+        let redis = probes
+            .pool
+            .get()
+            .ok()
+            .is_some_and(|con| redis::cmd("PING").query::<String>(&*con).is_ok());
+        let influxdb = probes.influx_client.ping();
+        if !redis {
+            problems.push("Redis is unreachable".to_owned());
+        }
+        if !influxdb {
+            problems.push("InfluxDB is unreachable".to_owned());
+        }
+        (Some(redis), Some(influxdb))
+    } else {
+        (None, None)
+    };
+
+    Some(Report {
+        healthy: problems.is_empty(),
+        problems,
+        redis,
+        influxdb,
+        secs_since_last_processed,
+        max_checkpoint_lag_secs: max_lag,
+    })
+}
@@ -0,0 +1,49 @@
+use crate::{health, metrics};
+use log::{info, warn};
+use std::io;
+use std::thread;
+use tiny_http::{Header, Request, Response, Server};
+
+// serves `/metrics`, and `/healthz` and `/readyz` for the listener, from a background thread
+pub fn serve(addr: &str) -> Result<(), io::Error> {
+    let server = Server::http(addr).map_err(|e| io::Error::other(e.to_string()))?;
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            if let Err(e) = respond(request) {
+                warn!("Failed to respond to an HTTP request: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn respond(request: Request) -> Result<(), io::Error> {
+    let report = match request.url() {
+        "/metrics" => {
+            let response = Response::from_data(metrics::render())
+                .with_header(header("Content-Type: text/plain; version=0.0.4"));
+            return request.respond(response);
+        }
+        "/healthz" => health::check(false),
+        "/readyz" => health::check(true),
+        _ => None,
+    };
+
+    match report {
+        Some(report) => {
+            let status = if report.healthy { 200 } else { 503 };
+            let response = Response::from_string(serde_json::to_string(&report).unwrap())
+                .with_status_code(status)
+                .with_header(header("Content-Type: application/json"));
+            request.respond(response)
+        }
+        None => request.respond(Response::from_string("Not Found").with_status_code(404)),
+    }
+}
+
+fn header(s: &str) -> Header {
+    s.parse::<Header>().unwrap()
+}
@@ -1,7 +1,9 @@
-use crate::{settings::Field, settings::FieldDataType};
+use chrono::NaiveDateTime;
+use crate::{metrics, settings::Field, settings::FieldDataType, throttle};
 use failure_derive::Fail;
 use influx_db_client::{error, Client, Node, Point, Points, Precision, Value as InfluxValue};
 use serde_json::Value;
+use std::time::Duration;
 
 #[derive(Debug, Deserialize)]
 pub enum FieldValue {
@@ -39,16 +41,16 @@ pub fn first_series_from_result(
     result: Result<Option<Vec<Node>>, error::Error>,
 ) -> Result<SeriesResult, Error> {
     let first_series = result
-        .map_err(|e| Error::InfluxDbAccessError(e))?
-        .ok_or_else(|| Error::NoResult)?
+        .map_err(Error::InfluxDbAccessError)?
+        .ok_or(Error::NoResult)?
         .into_iter()
         .next()
-        .ok_or_else(|| Error::NoResult)?
+        .ok_or(Error::NoResult)?
         .series
-        .ok_or_else(|| Error::NoResult)?
+        .ok_or(Error::NoResult)?
         .into_iter()
         .next()
-        .ok_or_else(|| Error::NoResult)?;
+        .ok_or(Error::NoResult)?;
 
     Ok(SeriesResult {
         values: first_series.values,
@@ -57,13 +59,16 @@ pub fn first_series_from_result(
 }
 
 pub fn get_range(client: &Client, query_str: &str) -> Result<SeriesResult, Error> {
-    let res = run_query(&client, query_str);
+    throttle::before_query();
+    let timer = metrics::QUERY_DURATION.start_timer();
+    let res = run_query(client, query_str);
+    throttle::record_query_latency(Duration::from_secs_f64(timer.stop_and_record()));
     first_series_from_result(res)
 }
 
 pub fn from_json_values(
-    vals: &Vec<Vec<Value>>,
-    fields: &Vec<Field>,
+    vals: &[Vec<Value>],
+    fields: &[Field],
 ) -> Result<Vec<Vec<FieldValue>>, Error> {
     vals.iter()
         .map(|vec| {
@@ -146,6 +151,8 @@ pub fn save_points(
     retention_policy: &str,
     points: Vec<Point>,
 ) -> Result<(), error::Error> {
+    throttle::before_write(points.len());
+    let _timer = metrics::WRITE_DURATION.start_timer();
     client.write_points(
         Points::create_new(points),
         Some(Precision::Nanoseconds),
@@ -154,3 +161,21 @@ pub fn save_points(
 
     Ok(())
 }
+
+// removes previously written points of a measurement in [start, end)
+pub fn delete_range(
+    client: &Client,
+    measurement: &str,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> Result<(), error::Error> {
+    let query = format!(
+        "DELETE FROM \"{}\" WHERE time >= {} AND time < {}",
+        measurement,
+        start.timestamp_nanos(),
+        end.timestamp_nanos()
+    );
+    run_query(client, &query)?;
+
+    Ok(())
+}
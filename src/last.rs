@@ -0,0 +1,59 @@
+use crate::lttb::DataPoint;
+
+// keeps the last point of each of `threshold` equal slices of the x range of `data`, which is sorted by x.
+// returns references to the original data like `lttb_downsample`, `None` keeps all of it.
+pub fn last_downsample<T: DataPoint>(data: &[T], threshold: usize, x_index: usize) -> Option<Vec<&T>> {
+    if threshold >= data.len() || threshold == 0 {
+        return None;
+    }
+
+    let first = data[0].get_x(x_index);
+    let width = (data[data.len() - 1].get_x(x_index) - first) / threshold as f64;
+
+    let mut sampled: Vec<&T> = Vec::with_capacity(threshold);
+    let mut current = None;
+    for point in data.iter() {
+        let slice = if width > 0.0 {
+            (((point.get_x(x_index) - first) / width) as usize).min(threshold - 1)
+        } else {
+            0
+        };
+        match sampled.last_mut() {
+            Some(last) if current == Some(slice) => *last = point,
+            _ => {
+                sampled.push(point);
+                current = Some(slice);
+            }
+        }
+    }
+
+    Some(sampled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl DataPoint for (f64, f64) {
+        fn get_x(&self, _index: usize) -> f64 {
+            self.0
+        }
+
+        fn get_y(&self, _index: usize) -> f64 {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_last_downsample() {
+        let data: Vec<(f64, f64)> = (0..10).map(|x| (x as f64, x as f64 * 10.0)).collect();
+        let sampled: Vec<f64> = last_downsample(&data, 3, 0)
+            .unwrap()
+            .iter()
+            .map(|point| point.0)
+            .collect();
+        assert_eq!(sampled, vec![2.0, 5.0, 9.0]);
+
+        assert!(last_downsample(&data, 10, 0).is_none());
+    }
+}
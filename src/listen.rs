@@ -1,32 +1,156 @@
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Utc};
+use crate::cluster::{Cluster, Loaded};
+use crate::dead_letters::{push_dead_letter, DeadLetter};
 use crate::downsampling::downsample_period;
-use crate::influx::influx_client;
+use crate::health::{self, Probes};
+use crate::influx::{self, delete_range, influx_client};
+use crate::metrics;
 use crate::settings::Interval;
-use crate::utils::time::{intervals, parse_timestamp_sec};
-use crate::{cmdargs::CmdArgs, settings::Config, utils::error::print_err_and_exit};
+use crate::settings::TimestampUnit;
+use crate::utils::rate_limit::RateLimiter;
+use crate::utils::shutdown;
+use crate::utils::time::{format_timestamp, interval_buckets, interval_start, parse_timestamp};
+use crate::selection::Selection;
+use crate::settings::Config;
+use crate::utils::error;
+use failure_derive::Fail;
 use influx_db_client::Client;
-use redis::Connection;
+use log::{debug, error, info, warn};
+use redis::{Connection, RedisError, Script};
 use std::collections::HashMap;
 use std::ops::Sub;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration as StdDuration;
+use std::time::{Duration as StdDuration, Instant};
 use string_template::Template;
 use time::Duration;
-//use rayon::prelude::*;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
 use r2d2_redis::{r2d2, RedisConnectionManager};
 use r2d2_redis::redis::{Commands, PipelineCommands};
 
 
 const UPDATES_TABLE_NAME: &str = "downsampler_updates";
-const CHECKPOINTS_TABLE_NAME: &str = "downsampler_checkpoints";
-
-pub fn listen(_args: &CmdArgs, config: &Config) -> () {
-    let influx_client = influx_client(
-        &config.influxdb.url,
-        &config.influxdb.db,
-        &config.influxdb.username,
-        &config.influxdb.pass,
-    );
+pub const CHECKPOINTS_TABLE_NAME: &str = "downsampler_checkpoints";
+// bumped with every edit of the `checkpoints` command, so that running listeners reload the checkpoints
+const CHECKPOINTS_VERSION_NAME: &str = "downsampler_checkpoints_version";
+const RECOMPUTED_TABLE_NAME: &str = "downsampler_recomputed";
+const STATUS_TABLE_NAME: &str = "downsampler_status";
+const REDIS_CONNECTION_TIMEOUT_SECS: u64 = 5;
+
+// KEYS[1]: updates hash, ARGV: id, timestamp pairs. deletes the updates that weren't overwritten since we read them
+const TAKE_UPDATES_SCRIPT: &str = r"
+for i = 1, #ARGV, 2 do
+    if redis.call('HGET', KEYS[1], ARGV[i]) == ARGV[i + 1] then
+        redis.call('HDEL', KEYS[1], ARGV[i])
+    end
+end
+return 0
+";
+
+// KEYS[1]: checkpoints hash, KEYS[2]: checkpoints version, ARGV: version the checkpoints were loaded at, key,
+// checkpoint. writes the checkpoint unless the checkpoints were edited since they were loaded
+const WRITE_CHECKPOINT_SCRIPT: &str = r"
+if tonumber(redis.call('GET', KEYS[2]) or '0') ~= tonumber(ARGV[1]) then
+    return 0
+end
+redis.call('HSET', KEYS[1], ARGV[2], ARGV[3])
+return 1
+";
+
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "Failed to get a Redis connection: {}", _0)]
+    RedisUnavailable(r2d2::Error),
+    #[fail(display = "Redis command failed: {}", _0)]
+    Redis(RedisError),
+    #[fail(display = "InfluxDB request failed: {}", _0)]
+    InfluxDb(influx::Error),
+    #[fail(display = "Checkpoints were edited since they were loaded")]
+    CheckpointsEdited,
+}
+
+impl Error {
+    // the `kind` label of the errors metric
+    fn kind(&self) -> &'static str {
+        match self {
+            Error::RedisUnavailable(_) | Error::Redis(_) => "redis",
+            Error::InfluxDb(_) => "influxdb",
+            Error::CheckpointsEdited => "checkpoints_edited",
+        }
+    }
+}
+
+// state shared by everything the listener processes
+struct Context<'a> {
+    config: &'a Config,
+    // updates of the ids it doesn't select are left in Redis
+    selection: &'a Selection,
+    influx_client: Client,
+    query_template: Template,
+    measurement_template: Template,
+    cluster: Option<Arc<Cluster>>,
+    // failed attempts per checkpoint key and period end
+    attempts: Mutex<HashMap<(String, NaiveDateTime), u32>>,
+    // version of the checkpoints hash the checkpoints were loaded at, checkpoints are only written while it's current
+    checkpoints_version: AtomicI64,
+}
+
+// tracks outages so the listener can back off and report that it's degraded instead of exiting
+struct Status {
+    degraded_since: Option<Instant>,
+    failures: u32,
+    last_error: Option<String>,
+}
+
+impl Status {
+    fn new() -> Status {
+        Status {
+            degraded_since: None,
+            failures: 0,
+            last_error: None,
+        }
+    }
+
+    fn succeeded(&mut self) {
+        if let Some(since) = self.degraded_since.take() {
+            info!(
+                "Listener recovered after {}s and {} failed attempts",
+                since.elapsed().as_secs(),
+                self.failures
+            );
+        }
+        self.failures = 0;
+        self.last_error = None;
+    }
+
+    fn failed(&mut self, e: &error::Error) {
+        if self.degraded_since.is_none() {
+            self.degraded_since = Some(Instant::now());
+        }
+        self.failures += 1;
+        self.last_error = Some(e.to_string());
+        let kind = match e {
+            error::Error::Listen(_, e) | error::Error::Listener(e) => e.kind(),
+            _ => "other",
+        };
+        metrics::ERRORS.with_label_values(&[kind]).inc();
+        warn!("Listener degraded ({} failed attempts): {}", self.failures, e);
+    }
+
+    // exponential backoff starting at the poll interval, capped at `max_backoff_ms`
+    fn backoff(&self, config: &Config) -> StdDuration {
+        let exp = self.failures.saturating_sub(1).min(16);
+        let ms = config.listen.poll_sleep_ms.saturating_mul(1 << exp);
+        StdDuration::from_millis(ms.min(config.listen.max_backoff_ms))
+    }
+}
+
+// runs every pipeline of the config side by side, sharing the Redis pool, the health probes and the cluster
+// membership. returns when shutdown is requested, errors past startup are retried
+pub fn listen(selection: &Selection, pipelines: &[Config]) -> Result<(), error::Error> {
+    let config = &pipelines[0];
 
 //    let client = redis::Client::open(config.listen.redis_url.as_str())
 //        .unwrap_or_else(|e| print_err_and_exit(e));
@@ -34,119 +158,583 @@ pub fn listen(_args: &CmdArgs, config: &Config) -> () {
 //        .get_connection()
 //        .unwrap_or_else(|e| print_err_and_exit(e));
 
-    let manager = RedisConnectionManager::new(config.listen.redis_url.as_str()).unwrap();
+    let manager = RedisConnectionManager::new(config.listen.redis_url.as_str())
+        .map_err(|e| error::Error::Listener(Error::Redis(e)))?;
+    // unchecked, so that we can start while Redis is down
     let pool = r2d2::Pool::builder()
-        .build(manager)
-        .unwrap();
+        .connection_timeout(StdDuration::from_secs(REDIS_CONNECTION_TIMEOUT_SECS))
+        .build_unchecked(manager);
 
-    let con = pool.get().unwrap_or_else(|e| print_err_and_exit(e));
-    let mut checkpoints = get_checkpoints(&con); // load checkpoints just once
+    let jobs: Vec<&str> = pipelines.iter().map(|pipeline| pipeline.job_label()).collect();
+    health::register(
+        Probes {
+            pool: pool.clone(),
+            influx_client: influx::influx_client(
+                &config.influxdb.url,
+                &config.influxdb.db,
+                &config.influxdb.username,
+                &config.influxdb.pass,
+            ),
+            max_idle_secs: config.listen.health_max_idle_secs,
+            max_checkpoint_lag_secs: config.listen.health_max_checkpoint_lag_secs,
+        },
+        &jobs,
+    );
 
-    println!("checkpoints: {:#?}", &checkpoints);
+    let cluster = if config.listen.cluster {
+        Some(Cluster::start(&pool, &config.listen))
+    } else {
+        None
+    };
 
-    let measurement_template = Template::new(&config.listen.measurement_template);
-    let query_template = Template::new(&config.listen.query_template);
+    thread::scope(|s| {
+        for pipeline in pipelines {
+            let (pool, cluster) = (&pool, cluster.clone());
+            s.spawn(move || listen_pipeline(selection, pipeline, pool, cluster));
+        }
+    });
+
+    if let Some(ref cluster) = cluster {
+        let res = pool
+            .get()
+            .map_err(Error::RedisUnavailable)
+            .and_then(|con| cluster.leave(&con).map_err(Error::Redis));
+        if let Err(e) = res {
+            error!("Failed to leave the cluster: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn listen_pipeline(
+    selection: &Selection,
+    config: &Config,
+    pool: &r2d2::Pool<RedisConnectionManager>,
+    cluster: Option<Arc<Cluster>>,
+) {
+    let ctx = Context {
+        config,
+        selection,
+        influx_client: influx_client(
+            &config.influxdb.url,
+            &config.influxdb.db,
+            &config.influxdb.username,
+            &config.influxdb.pass,
+        ),
+        query_template: Template::new(&config.listen.query_template),
+        measurement_template: Template::new(&config.listen.measurement_template),
+        cluster,
+        attempts: Mutex::new(HashMap::new()),
+        checkpoints_version: AtomicI64::new(0),
+    };
+
+    let mut checkpoints = None; // loaded just once, as soon as Redis is reachable
+    let mut loaded = Loaded::default(); // in a cluster, the leases the checkpoints are current for
+    let mut pending = HashMap::new(); // updates taken from Redis but not processed yet
+    let mut status = Status::new();
 
     // TODO: parallelize this. mutex around checkpoints?
-    loop {
-        let map = get_updates(&con); // get updates
-        if !map.is_empty() {
-            for (id, timestamp_str) in map.into_iter() {
-                let end = parse_timestamp_sec(timestamp_str.as_str());
-                process_period(
-                    config,
-                    &influx_client,
-                    id.as_str(),
-                    &end,
-                    &query_template,
-                    &measurement_template,
-                    &con,
-                    &mut checkpoints,
-                );
+    while !shutdown::requested() {
+        let res = poll(&ctx, pool, &mut checkpoints, &mut loaded, &mut pending);
+        metrics::QUEUE_DEPTH
+            .with_label_values(&[config.job_label()])
+            .set(pending.len() as i64);
+        match res {
+            Ok(processed) => {
+                let was_degraded = status.degraded_since.is_some();
+                status.succeeded();
+                if was_degraded {
+                    report_status(pool, config, &status, pending.len());
+                }
+                if !processed {
+                    // we didn't get updates this time, sleep a bit
+                    shutdown::sleep(StdDuration::from_millis(config.listen.poll_sleep_ms));
+                }
+            }
+            Err(e) => {
+                status.failed(&e);
+                report_status(pool, config, &status, pending.len());
+                shutdown::sleep(status.backoff(config));
+            }
+        }
+    }
+
+    info!("Shutting down, {} pending updates", pending.len());
+    let res = pool
+        .get()
+        .map_err(Error::RedisUnavailable)
+        .and_then(|con| return_updates(&con, config, &pending));
+    if let Err(e) = res {
+        error!("Failed to push pending updates back to Redis: {}", e);
+        error!("Lost updates: {:?}", &pending);
+    }
+}
+
+// takes new updates from Redis and processes everything pending. returns whether there was anything to process.
+// on error the unprocessed updates stay in `pending`, and the checkpoints make sure finished intervals aren't redone.
+fn poll(
+    ctx: &Context,
+    pool: &r2d2::Pool<RedisConnectionManager>,
+    checkpoints: &mut Option<HashMap<String, NaiveDateTime>>,
+    loaded: &mut Loaded,
+    pending: &mut HashMap<String, NaiveDateTime>,
+) -> Result<bool, error::Error> {
+    let con = pool
+        .get()
+        .map_err(|e| error::Error::Listener(Error::RedisUnavailable(e)))?;
+    let checkpoints = take_pending(ctx, pool, &con, checkpoints, loaded, pending)
+        .map_err(error::Error::Listener)?;
+
+    if pending.is_empty() {
+        health::record_idle(ctx.config.job_label());
+        return Ok(false);
+    }
+
+    let ids: Vec<String> = pending.keys().cloned().collect();
+    for id in ids {
+        if shutdown::requested() {
+            break;
+        }
+        let end = pending[&id];
+        // an id handed over since the updates were taken goes back to Redis with the next poll
+        let _work = match ctx.cluster {
+            Some(ref cluster) => match cluster.work_on(&id) {
+                Some(work) => {
+                    if !loaded.is_current(&work) {
+                        // acquired since we loaded it, the previous owner may have advanced its checkpoints
+                        reload_checkpoints(ctx, &con, checkpoints, &id).map_err(error::Error::Listener)?;
+                        loaded.insert(&work);
+                    }
+                    Some(work)
+                }
+                None => continue,
+            },
+            None => None,
+        };
+        match process_period(ctx, id.as_str(), &end, &con, checkpoints) {
+            // the update stays pending, to be processed from the edited checkpoints the next poll loads
+            Err(error::Error::Listen(_, Error::CheckpointsEdited)) => return Ok(true),
+            res => res?,
+        }
+        health::record_processed(ctx.config.job_label());
+        // an interrupted update is kept, to be pushed back to Redis
+        if !shutdown::requested() {
+            pending.remove(&id);
+        }
+    }
+
+    Ok(true)
+}
+
+// loads the checkpoints, or reloads them if they were edited, and adds the new updates to `pending`
+fn take_pending<'a>(
+    ctx: &Context,
+    pool: &r2d2::Pool<RedisConnectionManager>,
+    con: &Connection,
+    checkpoints: &'a mut Option<HashMap<String, NaiveDateTime>>,
+    loaded: &mut Loaded,
+    pending: &mut HashMap<String, NaiveDateTime>,
+) -> Result<&'a mut HashMap<String, NaiveDateTime>, Error> {
+    let checkpoints = match checkpoints {
+        Some(checkpoints) => {
+            reload_if_edited(ctx, con, checkpoints)?;
+            checkpoints
+        }
+        None => {
+            if let Some(ref cluster) = ctx.cluster {
+                // catch up only on the ids we're responsible for
+                let assigned: Vec<String> = ctx
+                    .config
+                    .vars
+                    .ids
+                    .iter()
+                    .filter(|id| cluster.is_assigned(id))
+                    .cloned()
+                    .collect();
+                cluster.acquire(con, &assigned).map_err(Error::Redis)?;
+                // taken before the checkpoints, so that a lease acquired in between gets them reloaded
+                *loaded = cluster.loaded();
+            }
+            // read before the checkpoints, so that an edit in between is noticed with the next poll
+            let version = get_checkpoints_version(con, ctx.config)?;
+            ctx.checkpoints_version.store(version, Ordering::SeqCst);
+            let mut stored = get_checkpoints(con, ctx.config)?;
+            info!("Loaded {} checkpoints", stored.len());
+            debug!("checkpoints: {:?}", &stored);
+            catch_up(ctx, pool, loaded, &mut stored);
+            checkpoints.get_or_insert(stored)
+        }
+    };
+
+    let updates = match ctx.cluster {
+        Some(ref cluster) => take_owned_updates(ctx, cluster, con)?,
+        None => get_updates(ctx, con)?,
+    };
+
+    for (id, timestamp_str) in updates.into_iter() {
+        let end = match parse_timestamp(&timestamp_str, ctx.config.listen.timestamp_unit) {
+            Ok(end) => end,
+            Err(e) => {
+                warn!(id = id.as_str(); "Rejected update: {}", e);
+                metrics::ERRORS.with_label_values(&["malformed_update"]).inc();
+                continue;
             }
-        } else {
-            // we didn't get updates this time, sleep a bit
-            thread::sleep(StdDuration::from_millis(config.listen.poll_sleep_ms));
+        };
+        // the updates hash only keeps the latest timestamp per id, so do we
+        let latest = pending.entry(id.clone()).or_insert(end);
+        if end > *latest {
+            *latest = end;
         }
+        for interval_period in ctx.config.downsampler.intervals.iter() {
+            if let Some(checkpoint) = checkpoints.get(&checkpoint_key(&id, interval_period)) {
+                record_lag(ctx.config, &id, interval_period, latest, *checkpoint);
+            }
+        }
+    }
+
+    if let Some(ref cluster) = ctx.cluster {
+        // ids rebalanced to another instance while we held updates for them go back to Redis
+        let lost: HashMap<String, NaiveDateTime> = pending
+            .iter()
+            .filter(|(id, _)| !cluster.owns(id))
+            .map(|(id, ts)| (id.clone(), *ts))
+            .collect();
+        return_updates(con, ctx.config, &lost)?;
+        pending.retain(|id, _| cluster.owns(id));
+    }
+
+    Ok(checkpoints)
+}
+
+// best effort, Redis may well be the reason we're degraded
+fn report_status(pool: &r2d2::Pool<RedisConnectionManager>, config: &Config, status: &Status, pending: usize) {
+    let state = if status.degraded_since.is_some() {
+        "degraded"
+    } else {
+        "ok"
+    };
+    let con = match pool.try_get() {
+        Some(con) => con,
+        None => return,
+    };
+    let res: Result<(), RedisError> = redis::cmd("HMSET")
+        .arg(config.redis_key(STATUS_TABLE_NAME))
+        .arg("state")
+        .arg(state)
+        .arg("updated_at")
+        .arg(Utc::now().to_rfc3339())
+        .arg("failures")
+        .arg(status.failures)
+        .arg("last_error")
+        .arg(status.last_error.as_ref().map_or("", |e| e.as_str()))
+        .arg("pending_updates")
+        .arg(pending)
+        .query(&*con);
+    if let Err(e) = res {
+        warn!("Failed to report listener status: {}", e);
     }
 }
 
 fn process_period(
-    config: &Config,
-    influx_client: &Client,
+    ctx: &Context,
     id: &str,
     period_end: &NaiveDateTime,
-    query_template: &Template,
-    measurement_template: &Template,
     con: &Connection,
-    checkpoints: &mut HashMap<String, i64>,
-) {
-    for interval_period in config.downsampler.intervals.iter() {
+    checkpoints: &mut HashMap<String, NaiveDateTime>,
+) -> Result<(), error::Error> {
+    for interval_period in ctx.config.downsampler.intervals.iter() {
         // check with each interval
         let key = checkpoint_key(id, interval_period);
+        let context = |period| error::Context::new(id, Some(&interval_period.name), period);
+
+        if let Some(checkpoint) = checkpoints.get(key.as_str()).cloned() {
+            if *period_end < checkpoint {
+                // data arrived for an interval we have already downsampled
+                process_late_period(ctx, id, period_end, &checkpoint, interval_period, con)?;
+                continue;
+            }
+        }
+
         let period_start = calc_period_start(interval_period, period_end, checkpoints, key.as_str());
 
-        for (_start, end) in intervals(
+        for (_start, end) in interval_buckets(
             period_start,
             *period_end,
-            Duration::seconds(interval_period.duration_secs as i64),
-        ) { // iterate the given period in duration_secs chunks
+            interval_period,
+        ) { // iterate the given period bucket by bucket
+            // a period is only abandoned between its write and the next one, never between write and checkpoint
+            if shutdown::requested() {
+                return Ok(());
+            }
             if end >= period_start && end <= *period_end {
-                println!("period_start: {:#?}, period_end: {:#?}, _start: {:#?}, end: {:#?}, interval_name: {:#?}",
-                         period_start,
-                         period_end,
-                         _start,
-                         end,
-                         interval_period.name);
+                debug!(id = id, interval = interval_period.name.as_str(), period:% = end;
+                       "Downsampling period from {} up to the update at {}", period_start, period_end);
 
                 let measurement_name = render_measurement_name(
                     id,
-                    &measurement_template,
+                    &ctx.measurement_template,
                     interval_period.name.as_str(),
                 );
-                downsample_period(
-                    config,
-                    &influx_client,
-                    &query_template,
-                    id,
-                    end,
-                    interval_period.duration_secs,
-                    measurement_name.as_str(),
-                );
+                run_period(ctx, con, id, interval_period, end, measurement_name.as_str())?;
 
-                set_checkpoint(&con, checkpoints, key.as_str(), end.timestamp());
+                set_checkpoint(con, ctx, checkpoints, key.as_str(), end)
+                    .map_err(|e| error::Error::listen(context(end), e))?;
+                record_lag(ctx.config, id, interval_period, period_end, end);
 
-                println!("Wrote checkpoint for interval {}, {:#?}", measurement_name, end);
+                info!(id = id, interval = interval_period.name.as_str(), period:% = end;
+                      "Wrote checkpoint for {}", measurement_name);
             }
         }
     }
+
+    Ok(())
+}
+
+// downsamples a single period. a period that keeps failing while InfluxDB itself is reachable
+// (e.g. a bad row) is pushed to the dead letters after `max_attempts` and skipped, so that it
+// doesn't block the id. failures during an outage don't count as attempts.
+fn run_period(
+    ctx: &Context,
+    con: &Connection,
+    id: &str,
+    interval_period: &Interval,
+    end: NaiveDateTime,
+    measurement_name: &str,
+) -> Result<(), error::Error> {
+    let context = || error::Context::new(id, Some(&interval_period.name), end);
+    let key = (checkpoint_key(id, interval_period), end);
+    let e = match downsample_period(
+        ctx.config,
+        &ctx.influx_client,
+        &ctx.query_template,
+        id,
+        end,
+        interval_period,
+        measurement_name,
+    ) {
+        Ok(_) => {
+            ctx.attempts.lock().unwrap().remove(&key);
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+
+    let max_attempts = ctx.config.listen.max_attempts;
+    if max_attempts == 0 || !ctx.influx_client.ping() {
+        return Err(error::Error::listen(context(), Error::InfluxDb(e)));
+    }
+
+    let attempts = {
+        let mut attempts = ctx.attempts.lock().unwrap();
+        let count = attempts.entry(key.clone()).or_insert(0);
+        *count += 1;
+        *count
+    };
+    if attempts < max_attempts {
+        return Err(error::Error::listen(context(), Error::InfluxDb(e)));
+    }
+
+    let start = interval_start(&end.sub(Duration::nanoseconds(1)), interval_period);
+    let dead_letter = DeadLetter::new(
+        id,
+        interval_period.name.as_str(),
+        start,
+        end,
+        e.to_string(),
+        attempts,
+    );
+    push_dead_letter(con, ctx.config, &dead_letter)
+        .map_err(|e| error::Error::listen(context(), Error::Redis(e)))?;
+    metrics::ERRORS.with_label_values(&["dead_letter"]).inc();
+    ctx.attempts.lock().unwrap().remove(&key);
+    error!(id = id, interval = interval_period.name.as_str(), period:% = end;
+           "Gave up after {} attempts, moved to dead letters: {}", attempts, e);
+
+    Ok(())
+}
+
+// recomputes and overwrites every interval between the one containing `period_end` and the checkpoint
+fn process_late_period(
+    ctx: &Context,
+    id: &str,
+    period_end: &NaiveDateTime,
+    checkpoint: &NaiveDateTime,
+    interval_period: &Interval,
+    con: &Connection,
+) -> Result<(), error::Error> {
+    let key = checkpoint_key(id, interval_period);
+    let context = |period| error::Context::new(id, Some(&interval_period.name), period);
+    let lateness = checkpoint.signed_duration_since(*period_end);
+    if lateness > Duration::seconds(ctx.config.listen.allowed_lateness_secs as i64) {
+        warn!(id = id, interval = interval_period.name.as_str(), period:% = period_end;
+              "Ignoring late update, {}s behind the checkpoint", lateness.num_seconds());
+        return Ok(());
+    }
+
+    let measurement_name =
+        render_measurement_name(id, &ctx.measurement_template, interval_period.name.as_str());
+
+    for (start, end) in interval_buckets(
+        interval_start(period_end, interval_period),
+        *checkpoint,
+        interval_period,
+    ) {
+        if shutdown::requested() {
+            return Ok(());
+        }
+        delete_range(&ctx.influx_client, measurement_name.as_str(), start, end)
+            .map_err(|e| {
+                error::Error::listen(context(end), Error::InfluxDb(influx::Error::InfluxDbAccessError(e)))
+            })?;
+
+        run_period(ctx, con, id, interval_period, end, measurement_name.as_str())?;
+
+        record_recomputed(con, ctx.config, key.as_str())
+            .map_err(|e| error::Error::listen(context(end), e))?;
+
+        info!(id = id, interval = interval_period.name.as_str(), period:% = end;
+              "Recomputed late interval {}", measurement_name);
+    }
+
+    Ok(())
+}
+
+// on startup, downsamples everything between the stored checkpoints and now in parallel,
+// so a long downtime doesn't turn the first update of every id into a huge serial catch-up
+fn catch_up(
+    ctx: &Context,
+    pool: &r2d2::Pool<RedisConnectionManager>,
+    loaded: &Loaded,
+    checkpoints: &mut HashMap<String, NaiveDateTime>,
+) {
+    let config = ctx.config;
+    if !config.listen.catchup {
+        return;
+    }
+
+    let now = Utc::now().naive_utc();
+    let stored: &HashMap<String, NaiveDateTime> = checkpoints;
+    let work: Vec<(&str, &Interval, NaiveDateTime)> = config
+        .vars
+        .ids
+        .iter()
+        .filter(|id| ctx.cluster.as_ref().is_none_or(|cluster| cluster.owns(id)))
+        .flat_map(|id| {
+            config.downsampler.intervals.iter().filter_map(move |interval_period| {
+                stored
+                    .get(&checkpoint_key(id, interval_period))
+                    .map(|checkpoint| (id.as_str(), interval_period, *checkpoint))
+            })
+        })
+        .collect();
+
+    let threads = match ThreadPoolBuilder::new()
+        .num_threads(config.listen.catchup_threads)
+        .build()
+    {
+        Ok(threads) => threads,
+        Err(e) => {
+            error!("Skipping catch-up, failed to start its threads: {}", e);
+            return;
+        }
+    };
+    let limiter = RateLimiter::new(config.listen.catchup_max_periods_per_sec);
+
+    info!("Catching up {} checkpoints to {}", work.len(), now);
+
+    let caught_up: Vec<(String, NaiveDateTime)> = threads.install(|| {
+        work.par_iter()
+            .filter_map(|(id, interval_period, checkpoint)| {
+                let _work = match ctx.cluster {
+                    Some(ref cluster) => {
+                        let work = cluster.work_on(id)?;
+                        // acquired again since the checkpoints were loaded, left to the live updates to reload
+                        if !loaded.is_current(&work) {
+                            return None;
+                        }
+                        Some(work)
+                    }
+                    None => None,
+                };
+                catch_up_interval(ctx, pool, &limiter, id, interval_period, *checkpoint, &now)
+                    .map(|ts| (checkpoint_key(id, interval_period), ts))
+            })
+            .collect()
+    });
+
+    info!("Caught up {} checkpoints", caught_up.len());
+    checkpoints.extend(caught_up);
+}
+
+// returns the last checkpoint written, if any. stops at the first error and leaves the rest to live updates.
+fn catch_up_interval(
+    ctx: &Context,
+    pool: &r2d2::Pool<RedisConnectionManager>,
+    limiter: &RateLimiter,
+    id: &str,
+    interval_period: &Interval,
+    checkpoint: NaiveDateTime,
+    now: &NaiveDateTime,
+) -> Option<NaiveDateTime> {
+    let key = checkpoint_key(id, interval_period);
+    let measurement_name =
+        render_measurement_name(id, &ctx.measurement_template, interval_period.name.as_str());
+    let mut caught_up = None;
+
+    for (_start, end) in interval_buckets(checkpoint, *now, interval_period) {
+        if shutdown::requested() {
+            break;
+        }
+        limiter.acquire();
+
+        let context = || error::Context::new(id, Some(&interval_period.name), end);
+        let res = pool
+            .get()
+            .map_err(|e| error::Error::listen(context(), Error::RedisUnavailable(e)))
+            .and_then(|con| {
+                run_period(ctx, &con, id, interval_period, end, measurement_name.as_str())?;
+                write_checkpoint(&con, ctx, key.as_str(), end)
+                    .map_err(|e| error::Error::listen(context(), e))
+            });
+
+        match res {
+            Ok(()) => {
+                record_lag(ctx.config, id, interval_period, now, end);
+                caught_up = Some(end)
+            }
+            Err(e) => {
+                warn!(id = id, interval = interval_period.name.as_str(), period:% = end;
+                      "Catch-up stopped: {}", e);
+                break;
+            }
+        }
+    }
+
+    caught_up
 }
 
 fn calc_period_start(
     interval_period: &Interval,
     period_end: &NaiveDateTime,
-    checkpoints: &mut HashMap<String, i64>,
+    checkpoints: &mut HashMap<String, NaiveDateTime>,
     key: &str,
 ) -> NaiveDateTime {
     match checkpoints.get(key) {
-        Some(ts) => NaiveDateTime::from_timestamp(*ts, 0), // start from last checkpoint if exists
+        Some(ts) => *ts, // start from last checkpoint if exists
         _ => {
             let period_start = period_end.sub(Duration::seconds(1)); // start from now minus 1 second if no checkpoint
-            NaiveDateTime::from_timestamp(
-                (period_start.timestamp() / interval_period.duration_secs as i64)
-                    * interval_period.duration_secs as i64,
-                0,
-            ) // round to the start of an interval
+            interval_start(&period_start, interval_period) // round to the start of an interval
         }
     }
 }
 
-fn checkpoint_key(id: &str, interval_period: &Interval) -> String {
+pub fn checkpoint_key(id: &str, interval_period: &Interval) -> String {
     let key = format!("{}_{}", id, interval_period.name);
     key
 }
 
-fn render_measurement_name(
+pub fn render_measurement_name(
     id: &str,
     measurement_template: &Template,
     interval_period: &str,
@@ -159,33 +747,250 @@ fn render_measurement_name(
 
 fn set_checkpoint(
     con: &Connection,
-    checkpoints: &mut HashMap<String, i64>,
+    ctx: &Context,
+    checkpoints: &mut HashMap<String, NaiveDateTime>,
     id: &str,
-    ts: i64,
-) -> () {
-    redis::cmd("HSET")
-        .arg(CHECKPOINTS_TABLE_NAME)
-        .arg(id)
-        .arg(ts)
-        .execute(con);
+    ts: NaiveDateTime,
+) -> Result<(), Error> {
+    write_checkpoint(con, ctx, id, ts)?;
     checkpoints.insert(id.to_owned(), ts);
+
+    Ok(())
 }
 
-fn get_updates(con: &Connection) -> HashMap<String, String> {
+// fails with `CheckpointsEdited` instead of overwriting an edit of the `checkpoints` command
+fn write_checkpoint(con: &Connection, ctx: &Context, id: &str, ts: NaiveDateTime) -> Result<(), Error> {
+    let written: i64 = Script::new(WRITE_CHECKPOINT_SCRIPT)
+        .key(ctx.config.redis_key(CHECKPOINTS_TABLE_NAME))
+        .key(ctx.config.redis_key(CHECKPOINTS_VERSION_NAME))
+        .arg(ctx.checkpoints_version.load(Ordering::SeqCst))
+        .arg(id)
+        .arg(checkpoint_value(&ts))
+        .invoke(con)
+        .map_err(Error::Redis)?;
+    if written == 0 {
+        return Err(Error::CheckpointsEdited);
+    }
+
+    Ok(())
+}
+
+// stored in nanoseconds to keep the full precision of the update timestamps
+fn checkpoint_value(ts: &NaiveDateTime) -> i64 {
+    ts.timestamp_nanos()
+}
+
+// writes and deletes checkpoints and bumps their version in one transaction, so that a running listener
+// reloads the checkpoints instead of overwriting the edit with the ones it has in memory
+pub fn edit_checkpoints(
+    con: &Connection,
+    config: &Config,
+    set: &[(String, NaiveDateTime)],
+    delete: &[String],
+) -> Result<(), Error> {
+    let table = config.redis_key(CHECKPOINTS_TABLE_NAME);
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for (key, ts) in set {
+        pipe.hset(&table, key, checkpoint_value(ts)).ignore();
+    }
+    for key in delete {
+        pipe.hdel(&table, key).ignore();
+    }
+    pipe.incr(config.redis_key(CHECKPOINTS_VERSION_NAME), 1).ignore();
+
+    pipe.query(con).map_err(Error::Redis)
+}
+
+fn get_checkpoints_version(con: &Connection, config: &Config) -> Result<i64, Error> {
+    let version: Option<i64> = con
+        .get(config.redis_key(CHECKPOINTS_VERSION_NAME))
+        .map_err(Error::Redis)?;
+
+    Ok(version.unwrap_or(0))
+}
+
+// replaces all checkpoints with the stored ones if the `checkpoints` command edited them since they were loaded
+fn reload_if_edited(
+    ctx: &Context,
+    con: &Connection,
+    checkpoints: &mut HashMap<String, NaiveDateTime>,
+) -> Result<(), Error> {
+    let version = get_checkpoints_version(con, ctx.config)?;
+    if version == ctx.checkpoints_version.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    *checkpoints = get_checkpoints(con, ctx.config)?;
+    ctx.checkpoints_version.store(version, Ordering::SeqCst);
+    info!("Checkpoints were edited, reloaded {} checkpoints", checkpoints.len());
+
+    Ok(())
+}
+
+// how far the checkpoint is behind the latest update of the id. the checkpoint is at the end of the last complete
+// bucket, so the health check only counts how far it is behind the start of the bucket the latest update is in:
+// a checkpoint that is up to date is up to a bucket behind the latest update.
+fn record_lag(
+    config: &Config,
+    id: &str,
+    interval_period: &Interval,
+    latest: &NaiveDateTime,
+    checkpoint: NaiveDateTime,
+) {
+    let lag = latest.signed_duration_since(checkpoint);
+    metrics::CHECKPOINT_LAG
+        .with_label_values(&[config.job_label(), id, interval_period.name.as_str()])
+        .set(lag.num_seconds());
+    let behind = interval_start(latest, interval_period).signed_duration_since(checkpoint);
+    let key = config.redis_key(&checkpoint_key(id, interval_period));
+    health::record_lag(&key, behind.num_seconds().max(0));
+}
+
+// counts recomputations per checkpoint key, to see how often late data is delivered
+fn record_recomputed(con: &Connection, config: &Config, key: &str) -> Result<(), Error> {
+    redis::cmd("HINCRBY")
+        .arg(config.redis_key(RECOMPUTED_TABLE_NAME))
+        .arg(key)
+        .arg(1)
+        .query(con)
+        .map_err(Error::Redis)
+}
+
+// puts updates we took but didn't process back into the updates hash.
+// an update a producer wrote in the meantime is newer than ours, so it wins.
+fn return_updates(
+    con: &Connection,
+    config: &Config,
+    pending: &HashMap<String, NaiveDateTime>,
+) -> Result<(), Error> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    let updates = config.redis_key(UPDATES_TABLE_NAME);
+    let mut pipe = redis::pipe();
+    for (id, ts) in pending.iter() {
+        pipe.hset_nx(&updates, id, format_timestamp(ts, config.listen.timestamp_unit))
+            .ignore();
+    }
+
+    pipe.query(con).map_err(Error::Redis)
+}
+
+// in a cluster, several instances read the updates hash, so each one only takes the updates of the ids it
+// holds the lease of, and only if they weren't overwritten in the meantime. the rest is left to their owners.
+fn take_owned_updates(
+    ctx: &Context,
+    cluster: &Cluster,
+    con: &Connection,
+) -> Result<HashMap<String, String>, Error> {
+    let updates: HashMap<String, String> =
+        con.hgetall(ctx.config.redis_key(UPDATES_TABLE_NAME)).map_err(Error::Redis)?;
+
+    let assigned: Vec<String> = updates
+        .keys()
+        .filter(|id| cluster.is_assigned(id) && ctx.selection.matches_id(id))
+        .cloned()
+        .collect();
+    cluster.acquire(con, &assigned).map_err(Error::Redis)?;
+
+    let owned: HashMap<String, String> = updates
+        .into_iter()
+        .filter(|(id, _)| cluster.owns(id))
+        .collect();
+    take_updates(con, ctx.config, owned)
+}
+
+// removes the given updates from Redis unless they were overwritten since they were read
+fn take_updates(
+    con: &Connection,
+    config: &Config,
+    owned: HashMap<String, String>,
+) -> Result<HashMap<String, String>, Error> {
+    if owned.is_empty() {
+        return Ok(owned);
+    }
+
+    let script = Script::new(TAKE_UPDATES_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    invocation.key(config.redis_key(UPDATES_TABLE_NAME));
+    for (id, ts) in owned.iter() {
+        invocation.arg(id).arg(ts);
+    }
+    invocation.invoke::<()>(con).map_err(Error::Redis)?;
+
+    Ok(owned)
+}
+
+// replaces the checkpoints of the id with the stored ones
+fn reload_checkpoints(
+    ctx: &Context,
+    con: &Connection,
+    checkpoints: &mut HashMap<String, NaiveDateTime>,
+    id: &str,
+) -> Result<(), Error> {
+    let keys: Vec<String> = ctx
+        .config
+        .downsampler
+        .intervals
+        .iter()
+        .map(|interval_period| checkpoint_key(id, interval_period))
+        .collect();
+    let stored: Vec<Option<String>> = redis::cmd("HMGET")
+        .arg(ctx.config.redis_key(CHECKPOINTS_TABLE_NAME))
+        .arg(&keys[..])
+        .query(con)
+        .map_err(Error::Redis)?;
+
+    for (key, ts) in keys.into_iter().zip(stored) {
+        match ts.map(|ts| parse_timestamp(&ts, TimestampUnit::Auto)) {
+            Some(Ok(ts)) => checkpoints.insert(key, ts),
+            Some(Err(e)) => {
+                warn!("Ignoring checkpoint {}: {}", key, e);
+                checkpoints.remove(&key)
+            }
+            None => checkpoints.remove(&key),
+        };
+    }
+    debug!(id = id; "Reloaded the checkpoints");
+
+    Ok(())
+}
+
+fn get_updates(ctx: &Context, con: &Connection) -> Result<HashMap<String, String>, Error> {
+    let updates_key = ctx.config.redis_key(UPDATES_TABLE_NAME);
+    if ctx.selection.is_narrowing() {
+        let updates: HashMap<String, String> = con.hgetall(&updates_key).map_err(Error::Redis)?;
+        let selected = updates
+            .into_iter()
+            .filter(|(id, _)| ctx.selection.matches_id(id))
+            .collect();
+        return take_updates(con, ctx.config, selected);
+    }
+
     let (map, _): (HashMap<String, String>, i32) = redis::pipe()
         .atomic()
-        .hgetall(UPDATES_TABLE_NAME)
-        .del(UPDATES_TABLE_NAME)
+        .hgetall(&updates_key)
+        .del(&updates_key)
         .query(con)
-        .unwrap();
+        .map_err(Error::Redis)?;
 
-    map
+    Ok(map)
 }
 
-fn get_checkpoints(con: &Connection) -> HashMap<String, i64> {
-    let map: HashMap<String, String> = con.hgetall(CHECKPOINTS_TABLE_NAME).unwrap();
+pub fn get_checkpoints(con: &Connection, config: &Config) -> Result<HashMap<String, NaiveDateTime>, Error> {
+    let map: HashMap<String, String> = con.hgetall(config.redis_key(CHECKPOINTS_TABLE_NAME)).map_err(Error::Redis)?;
 
-    map.into_iter()
-        .map(|(k, ts)| (k, ts.parse::<i64>().unwrap()))
-        .collect::<HashMap<String, i64>>()
+    // checkpoints written by older versions are in seconds, auto detection handles both
+    Ok(map
+        .into_iter()
+        .filter_map(|(k, ts)| match parse_timestamp(&ts, TimestampUnit::Auto) {
+            Ok(ts) => Some((k, ts)),
+            Err(e) => {
+                warn!("Ignoring checkpoint {}: {}", k, e);
+                None
+            }
+        })
+        .collect::<HashMap<String, NaiveDateTime>>())
 }
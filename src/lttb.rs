@@ -44,7 +44,7 @@ pub fn lttb_downsample<T: DataPoint>(
         let avg_range_length = (avg_range_end - avg_range_start) as f64;
 
         for i in 0..(avg_range_end - avg_range_start) {
-            let idx = (avg_range_start + i) as usize;
+            let idx = avg_range_start + i;
             avg_x += data[idx].get_x(x_index);
             avg_y += data[idx].get_y(y_index);
         }
@@ -62,7 +62,7 @@ pub fn lttb_downsample<T: DataPoint>(
         let mut max_area = -1f64;
         let mut next_a = range_offs;
         for i in 0..(range_to - range_offs) {
-            let idx = (range_offs + i) as usize;
+            let idx = range_offs + i;
 
             // Calculate triangle area over three buckets.
             let area = ((point_a_x - avg_x) * (data[idx].get_y(y_index) - point_a_y)
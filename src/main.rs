@@ -1,34 +1,184 @@
+// `#[derive(Fail)]` of failure 0.1 implements its traits inside a function body
+#![allow(non_local_definitions)]
+// the error variants are matched on by name throughout, e.g. `Error::DateParseError`
+#![allow(clippy::enum_variant_names)]
+
 #[macro_use]
 extern crate serde_derive;
 
+mod backfill;
+mod check_config;
+mod checkpoints;
+mod chunk_size;
+mod cluster;
 mod cmdargs;
+mod dead_letters;
 mod downsampler;
 mod downsampling;
+mod dry_run;
+mod health;
+mod http;
 mod influx;
+mod last;
 mod listen;
 mod lttb;
+mod metrics;
+mod progress;
+mod schedule;
+mod selection;
 mod settings;
 mod splitter;
+mod throttle;
 mod utils;
 
 use crate::{
-    cmdargs::{parse_args, print_args_info, CmdArgs},
+    backfill::{backfill, BackfillAction},
+    check_config::check_config,
+    checkpoints::checkpoints,
+    cmdargs::{parse_args, print_args_info, BatchArgs, CmdArgs},
+    dead_letters::dead_letters,
     downsampler::downsample,
+    dry_run::{dry_run_downsample, dry_run_split},
     listen::listen,
-    settings::config_from_file,
+    progress::Outcome,
+    settings::{config_from_file, Config},
     splitter::split,
-    utils::error::print_err_and_exit,
+    utils::{error::print_err_and_exit, logging, shutdown},
 };
+use config::ConfigError;
+use log::warn;
+use rayon::ThreadPoolBuilder;
+use std::process::exit;
+
+// exit code of a batch run that was stopped by SIGINT/SIGTERM before it completed
+const EXIT_INTERRUPTED: i32 = 130;
+// exit code of `check-config` when it found problems
+const EXIT_INVALID_CONFIG: i32 = 1;
+// exit code of a batch run where some ids failed and the others completed
+const EXIT_PARTIAL_FAILURE: i32 = 2;
+// exit code of a batch run where no id completed because of errors
+const EXIT_FAILURE: i32 = 3;
+// anything that prevents a command from starting, like bad arguments or config, exits with 255
 
 fn main() {
-    let args = parse_args().unwrap_or_else(|e| print_err_and_exit(e));
-    print_args_info(&args);
+    logging::init();
+    let mut args = parse_args().unwrap_or_else(|e| print_err_and_exit(e));
+    shutdown::install_handlers().unwrap_or_else(|e| print_err_and_exit(e));
+    print_args_info(&args.command);
+
+    let mut settings =
+        config_from_file(&args.config_path).unwrap_or_else(|e| print_err_and_exit(e));
+    logging::set_format(settings.log.format);
 
-    let settings = config_from_file("config").unwrap_or_else(|e| print_err_and_exit(e));
+    if let CmdArgs::Downsample(BatchArgs { limits, .. })
+    | CmdArgs::Split(BatchArgs { limits, .. })
+    | CmdArgs::Backfill(BackfillAction::Work(limits)) = &args.command
+    {
+        limits.apply(&mut settings.limits);
+    }
+    throttle::init(&settings.limits);
+    if settings.limits.workers > 0 {
+        ThreadPoolBuilder::new()
+            .num_threads(settings.limits.workers)
+            .build_global()
+            .unwrap_or_else(|e| print_err_and_exit(e));
+    }
 
-    match args {
-        CmdArgs::Downsample(period) => downsample(&period, &settings),
-        CmdArgs::Split(period) => split(&period, &settings),
-        CmdArgs::Listen => listen(&args, &settings),
+    // `listen` and `check-config` work on every job of the config, the other commands on the one picked
+    let mut pipelines = settings
+        .pipelines(args.job.as_deref())
+        .unwrap_or_else(|e| print_err_and_exit(e));
+    let all_jobs = matches!(args.command, CmdArgs::Listen(_) | CmdArgs::CheckConfig(_));
+    if pipelines.len() > 1 && !all_jobs {
+        print_err_and_exit(ConfigError::Message(format!(
+            "The config has jobs, pick one with --job: {}",
+            settings.job_names().join(", ")
+        )));
+    }
+    if let CmdArgs::Downsample(BatchArgs { selection, .. })
+    | CmdArgs::Split(BatchArgs { selection, .. })
+    | CmdArgs::Listen(selection)
+    | CmdArgs::Backfill(BackfillAction::Coordinate(_, selection)) = &mut args.command
+    {
+        selection
+            .apply_all(&mut pipelines)
+            .unwrap_or_else(|e| print_err_and_exit(e));
+    }
+    let settings = &pipelines[0];
+
+    let serve_metrics = match &args.command {
+        CmdArgs::Downsample(batch_args) | CmdArgs::Split(batch_args) => {
+            batch_args.metrics && !batch_args.dry_run
+        }
+        CmdArgs::Listen(_) | CmdArgs::Backfill(BackfillAction::Work(_)) => true,
+        _ => false,
     };
+    if serve_metrics {
+        match settings.metrics {
+            Some(ref metrics) => http::serve(&metrics.addr).unwrap_or_else(|e| print_err_and_exit(e)),
+            None => warn_without_metrics(&args.command, settings),
+        }
+    }
+
+    // only batch runs and the backfill coordinator have an outcome
+    let outcome = match &args.command {
+        CmdArgs::Downsample(batch_args) if batch_args.dry_run => {
+            dry_run_downsample(batch_args, settings);
+            None
+        }
+        CmdArgs::Split(batch_args) if batch_args.dry_run => {
+            dry_run_split(batch_args, settings);
+            None
+        }
+        CmdArgs::Downsample(batch_args) => Some(downsample(batch_args, settings)),
+        CmdArgs::Split(batch_args) => Some(split(batch_args, settings)),
+        CmdArgs::Listen(selection) => {
+            listen(selection, &pipelines).unwrap_or_else(|e| print_err_and_exit(e));
+            None
+        }
+        CmdArgs::DeadLetters(action) => {
+            dead_letters(action, settings);
+            None
+        }
+        CmdArgs::Checkpoints(action) => {
+            checkpoints(action, settings);
+            None
+        }
+        CmdArgs::Backfill(action) => backfill(action, settings),
+        CmdArgs::CheckConfig(check_args) => {
+            let mut valid = true;
+            for pipeline in pipelines.iter() {
+                if let Some(ref job) = pipeline.job {
+                    println!("Job {}:", job);
+                }
+                valid &= check_config(check_args, pipeline);
+            }
+            if !valid {
+                exit(EXIT_INVALID_CONFIG);
+            }
+            None
+        }
+    };
+
+    match outcome {
+        Some(Outcome::Interrupted) => exit(EXIT_INTERRUPTED),
+        Some(Outcome::PartiallyFailed) => exit(EXIT_PARTIAL_FAILURE),
+        Some(Outcome::Failed) => exit(EXIT_FAILURE),
+        Some(Outcome::Completed) | None => {}
+    }
+}
+
+// the endpoints are served on `metrics.addr`, asking for them without it would otherwise do nothing
+fn warn_without_metrics(command: &CmdArgs, settings: &Config) {
+    match command {
+        CmdArgs::Downsample(_) | CmdArgs::Split(_) => {
+            warn!("--metrics needs a [metrics] section with the address to serve them on, not serving metrics")
+        }
+        CmdArgs::Listen(_)
+            if settings.listen.health_max_idle_secs > 0 || settings.listen.health_max_checkpoint_lag_secs > 0 =>
+        {
+            warn!("listen.health_max_idle and health_max_checkpoint_lag need a [metrics] section, /healthz and /readyz are not served")
+        }
+        _ => {}
+    }
 }
@@ -0,0 +1,63 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, Histogram, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref POINTS_READ: IntCounterVec = register_int_counter_vec!(
+        "downsampler_points_read_total",
+        "Points read from InfluxDB",
+        &["job", "id", "interval", "op"]
+    )
+    .unwrap();
+    pub static ref POINTS_WRITTEN: IntCounterVec = register_int_counter_vec!(
+        "downsampler_points_written_total",
+        "Points written to InfluxDB",
+        &["job", "id", "interval", "op"]
+    )
+    .unwrap();
+    pub static ref QUERY_DURATION: Histogram = register_histogram!(
+        "downsampler_query_duration_seconds",
+        "Latency of InfluxDB queries"
+    )
+    .unwrap();
+    pub static ref WRITE_DURATION: Histogram = register_histogram!(
+        "downsampler_write_duration_seconds",
+        "Latency of InfluxDB writes"
+    )
+    .unwrap();
+    pub static ref QUERY_RATE_LIMIT: IntGauge = register_int_gauge!(
+        "downsampler_query_rate_limit",
+        "Queries per second currently allowed, 0 is unlimited"
+    )
+    .unwrap();
+    pub static ref CHECKPOINT_LAG: IntGaugeVec = register_int_gauge_vec!(
+        "downsampler_checkpoint_lag_seconds",
+        "Time between the latest update and the checkpoint",
+        &["job", "id", "interval"]
+    )
+    .unwrap();
+    pub static ref ERRORS: IntCounterVec = register_int_counter_vec!(
+        "downsampler_errors_total",
+        "Errors by kind",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref QUEUE_DEPTH: IntGaugeVec = register_int_gauge_vec!(
+        "downsampler_queue_depth",
+        "Updates taken from Redis that haven't been processed yet",
+        &["job"]
+    )
+    .unwrap();
+}
+
+// all metrics in the Prometheus text format
+pub fn render() -> Vec<u8> {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+
+    buffer
+}
@@ -0,0 +1,371 @@
+use chrono::NaiveDateTime;
+use crate::cmdargs::{ErrorPolicy, TimePeriod};
+use crate::utils::error::Error;
+use crate::utils::shutdown;
+use humantime::format_duration;
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+
+// how often a batch run logs its progress
+const PROGRESS_INTERVAL_SECS: u64 = 10;
+
+// tracks a batch run across the rayon threads, logs its progress and summarizes it at the end
+pub struct Progress {
+    command: &'static str,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    started: Instant,
+    ids: Mutex<BTreeMap<String, IdProgress>>,
+    // set when an id failed with `ErrorPolicy::FailFast`
+    aborted: AtomicBool,
+}
+
+#[derive(Serialize, Default, Clone)]
+pub struct IdStats {
+    pub queries: u64,
+    pub points_in: u64,
+    pub points_out: u64,
+    pub errors: u64,
+    pub duration_secs: f64,
+}
+
+#[derive(Default)]
+struct IdProgress {
+    stats: IdStats,
+    started: Option<Instant>,
+    // the id is done once this many of its work units are
+    expected_units: u64,
+    units_done: u64,
+    // the latest end of a finished unit, and the earliest of a unit that was given up on when stopping
+    done_through: Option<NaiveDateTime>,
+    first_unfinished: Option<NaiveDateTime>,
+}
+
+impl IdProgress {
+    fn done(&self) -> bool {
+        self.stats.errors == 0 && self.units_done >= self.expected_units
+    }
+
+    // the share of the id's work units that are done
+    fn covered(&self) -> f64 {
+        match self.expected_units {
+            0 => 1.0,
+            expected => (self.units_done as f64 / expected as f64).min(1.0),
+        }
+    }
+}
+
+// how a batch run ended, `main` exits with a code per outcome
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Completed,
+    // stopped by a signal or by another id failing with `--on-error fail-fast`, without errors
+    Interrupted,
+    // some ids failed, at least one completed
+    PartiallyFailed,
+    // no id completed
+    Failed,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub command: &'static str,
+    pub start: String,
+    pub end: String,
+    pub outcome: Outcome,
+    pub duration_secs: f64,
+    pub ids: BTreeMap<String, IdStats>,
+}
+
+impl Progress {
+    pub fn new(command: &'static str, ids: &[String], period: &TimePeriod) -> Progress {
+        Progress {
+            command,
+            start: period.start,
+            end: period.end,
+            started: Instant::now(),
+            ids: Mutex::new(
+                ids.iter()
+                    .map(|id| (id.clone(), IdProgress::default()))
+                    .collect(),
+            ),
+            aborted: AtomicBool::new(false),
+        }
+    }
+
+    pub fn expect_units(&self, id: &str, units: u64) {
+        self.update(id, |progress| progress.expected_units += units);
+    }
+
+    pub fn finish_unit(&self, id: &str, end: NaiveDateTime) {
+        self.update(id, |progress| {
+            progress.units_done += 1;
+            progress.done_through = progress.done_through.max(Some(end));
+        });
+    }
+
+    // a unit ending at `end` was taken but not run because the run is stopping
+    pub fn skip_unit(&self, id: &str, end: NaiveDateTime) {
+        self.update(id, |progress| {
+            let first = progress.first_unfinished.get_or_insert(end);
+            *first = (*first).min(end);
+        });
+    }
+
+    // where each id with units left stopped, for runs that take their units in the order of their ends per id:
+    // its earliest skipped unit, or else the latest one done, as the units after it weren't taken.
+    // an id that wasn't started stopped at the start of the run.
+    pub fn stopped_at(&self) -> Vec<(String, NaiveDateTime)> {
+        self.ids
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, progress)| progress.units_done < progress.expected_units)
+            .map(|(id, progress)| {
+                let at = progress
+                    .first_unfinished
+                    .or(progress.done_through)
+                    .unwrap_or(self.start);
+                (id.clone(), at)
+            })
+            .collect()
+    }
+
+    // from the first call on the id's duration is measured
+    pub fn start_id(&self, id: &str) {
+        self.update(id, |progress| {
+            progress.started.get_or_insert_with(Instant::now);
+        });
+    }
+
+    // a query returned `points_in` points, of which `points_out` were written
+    pub fn record_query(&self, id: &str, points_in: usize, points_out: usize) {
+        self.update(id, |progress| {
+            progress.stats.queries += 1;
+            progress.stats.points_in += points_in as u64;
+            progress.stats.points_out += points_out as u64;
+        });
+    }
+
+    pub fn record_error(&self, id: &str) {
+        self.update(id, |progress| progress.stats.errors += 1);
+    }
+
+    // the failed id is given up on, the others carry on unless the policy is to fail fast
+    pub fn record_failure(&self, id: &str, e: &Error, policy: ErrorPolicy) {
+        error!(id = id; "{}", e);
+        self.record_error(id);
+        if policy == ErrorPolicy::FailFast {
+            self.aborted.store(true, Ordering::SeqCst);
+        }
+    }
+
+    // whether the ids should stop at their next period, either on a signal or after a failure
+    pub fn stopping(&self) -> bool {
+        shutdown::requested() || self.aborted.load(Ordering::SeqCst)
+    }
+
+    pub fn has_failed(&self, id: &str) -> bool {
+        self.ids
+            .lock()
+            .unwrap()
+            .get(id)
+            .is_some_and(|progress| progress.stats.errors > 0)
+    }
+
+    // per id that didn't fail, the earliest of the times its work stopped at. a run starting there
+    // redoes what's left of the id, everything before it has been written.
+    pub fn resume_points<'a, I>(&self, stopped: I) -> Vec<(&'a str, NaiveDateTime)>
+    where
+        I: IntoIterator<Item = (&'a str, NaiveDateTime)>,
+    {
+        let mut resume: BTreeMap<&str, NaiveDateTime> = BTreeMap::new();
+        for (id, at) in stopped.into_iter().filter(|(id, _)| !self.has_failed(id)) {
+            let earliest = resume.entry(id).or_insert(at);
+            *earliest = (*earliest).min(at);
+        }
+
+        resume.into_iter().collect()
+    }
+
+    fn update<F: FnOnce(&mut IdProgress)>(&self, id: &str, f: F) {
+        let mut ids = self.ids.lock().unwrap();
+        let progress = ids.entry(id.to_owned()).or_default();
+        f(progress);
+        if let Some(started) = progress.started {
+            progress.stats.duration_secs = started.elapsed().as_secs_f64();
+        }
+    }
+
+    // runs `work` while logging the progress in the background
+    pub fn run<T, F: FnOnce() -> T>(&self, work: F) -> T {
+        let (stop, stopped) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(move || self.log_periodically(stopped));
+            let res = work();
+            drop(stop);
+            res
+        })
+    }
+
+    // logs the summary and writes the report, if one was asked for
+    pub fn finish(&self, report_path: Option<&str>) -> Outcome {
+        let report = self.report();
+        report.log_summary();
+        if let Some(path) = report_path {
+            report
+                .write(path)
+                .unwrap_or_else(|e| error!("Failed to write the report to {}: {}", path, e));
+        }
+
+        report.outcome
+    }
+
+    fn outcome(ids: &BTreeMap<String, IdProgress>) -> Outcome {
+        let failed = ids.values().filter(|progress| progress.stats.errors > 0).count();
+        let completed = ids.values().filter(|progress| progress.done()).count();
+
+        if failed == 0 {
+            if completed == ids.len() {
+                Outcome::Completed
+            } else {
+                Outcome::Interrupted
+            }
+        } else if completed > 0 {
+            Outcome::PartiallyFailed
+        } else {
+            Outcome::Failed
+        }
+    }
+
+    // logs the progress every `PROGRESS_INTERVAL_SECS` until the sender of `stop` is dropped
+    fn log_periodically(&self, stop: Receiver<()>) {
+        while let Err(RecvTimeoutError::Timeout) =
+            stop.recv_timeout(StdDuration::from_secs(PROGRESS_INTERVAL_SECS))
+        {
+            self.log();
+        }
+    }
+
+    fn log(&self) {
+        let ids = self.ids.lock().unwrap();
+        let done = ids.values().filter(|progress| progress.done()).count();
+        let (points_in, points_out) = ids.values().fold((0, 0), |(points_in, points_out), progress| {
+            (
+                points_in + progress.stats.points_in,
+                points_out + progress.stats.points_out,
+            )
+        });
+
+        let covered: f64 =
+            ids.values().map(IdProgress::covered).sum::<f64>() / ids.len().max(1) as f64;
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let eta = if covered > 0.0 {
+            format_duration(StdDuration::from_secs((elapsed * (1.0 - covered) / covered) as u64))
+                .to_string()
+        } else {
+            "unknown".to_owned()
+        };
+
+        info!(
+            "{}/{} ids done, {:.1}% of {} - {} covered, {:.0} points/s read, {:.0} points/s written, ETA {}",
+            done,
+            ids.len(),
+            covered * 100.0,
+            self.start,
+            self.end,
+            points_in as f64 / elapsed.max(1.0),
+            points_out as f64 / elapsed.max(1.0),
+            eta
+        );
+    }
+
+    pub fn report(&self) -> Report {
+        let ids = self.ids.lock().unwrap();
+        Report {
+            command: self.command,
+            start: self.start.to_string(),
+            end: self.end.to_string(),
+            outcome: Progress::outcome(&ids),
+            duration_secs: self.started.elapsed().as_secs_f64(),
+            ids: ids
+                .iter()
+                .map(|(id, progress)| (id.clone(), progress.stats.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl Report {
+    pub fn log_summary(&self) {
+        let total = self.ids.values().fold(IdStats::default(), |mut total, stats| {
+            total.queries += stats.queries;
+            total.points_in += stats.points_in;
+            total.points_out += stats.points_out;
+            total.errors += stats.errors;
+            total
+        });
+        info!(
+            "{:?} with {} ids in {}: {} queries, {} points read, {} points written, {} errors",
+            self.outcome,
+            self.ids.len(),
+            format_duration(StdDuration::from_secs(self.duration_secs as u64)),
+            total.queries,
+            total.points_in,
+            total.points_out,
+            total.errors
+        );
+    }
+
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmdargs::Align;
+
+    #[test]
+    fn test_report() {
+        let start = NaiveDateTime::from_timestamp(0, 0);
+        let period = TimePeriod {
+            raw_start: start,
+            raw_end: start + time::Duration::hours(1),
+            start,
+            end: start + time::Duration::hours(1),
+            align: Align::Minute,
+        };
+        let progress = Progress::new("split", &["a".to_owned(), "b".to_owned()], &period);
+        progress.expect_units("a", 2);
+        progress.start_id("a");
+        progress.record_query("a", 100, 10);
+        progress.record_query("a", 50, 5);
+        progress.finish_unit("a", start);
+        progress.record_error("b");
+
+        assert_eq!(progress.report().outcome, Outcome::Failed);
+        progress.record_query("a", 0, 0);
+        // `a` has one of its two units left, `b` expects none
+        assert_eq!(progress.stopped_at(), vec![("a".to_owned(), start)]);
+        progress.finish_unit("a", start + time::Duration::minutes(1));
+
+        let report = progress.report();
+        assert_eq!(report.outcome, Outcome::PartiallyFailed);
+        assert_eq!(report.ids.len(), 2);
+        assert_eq!(report.ids["a"].queries, 3);
+        assert_eq!(report.ids["a"].points_in, 150);
+        assert_eq!(report.ids["a"].points_out, 15);
+        assert_eq!(report.ids["b"].errors, 1);
+    }
+}
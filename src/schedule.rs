@@ -0,0 +1,294 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use crate::cluster::rendezvous_weight;
+use crate::settings::{Interval, IntervalKind};
+use crate::utils::time::{interval_start, next_interval_start};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::slice;
+use time::Duration;
+
+// what `downsample` does over a period, as work units generated straight from the interval boundaries.
+// they can be ordered, sharded and run in parallel across ids and time. units are generated as they are
+// consumed, a long period over many ids has far too many of them to hold at once.
+
+// one downsampling query: the bucket of `interval_period` between `start` and `end` of `id`
+#[derive(Debug, Clone, Copy)]
+pub struct WorkUnit<'a> {
+    pub id: &'a str,
+    pub interval_period: &'a Interval,
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    // id by id, each from the oldest bucket on
+    Id,
+    // the oldest buckets of all ids first
+    Time,
+}
+
+// one of `count` disjoint parts of a schedule, by id and UTC day, e.g. to spread a backfill over machines.
+// uses the same stable hash the cluster assigns ids with, so every process agrees on the parts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shard {
+    pub index: u64,
+    pub count: u64,
+}
+
+impl Shard {
+    pub fn contains(&self, unit: &WorkUnit) -> bool {
+        self.contains_day(unit.end.date(), unit.id)
+    }
+
+    fn contains_day(&self, day: NaiveDate, id: &str) -> bool {
+        rendezvous_weight(&day.to_string(), id) % self.count == self.index
+    }
+}
+
+// the work units of a run over [start, end). the units of an id are in the order of their ends in either
+// order, and buckets of an id ending at the same time are in the order of the intervals.
+pub fn schedule<'a>(
+    ids: &'a [String],
+    intervals: &'a [Interval],
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    order: Order,
+    shard: Option<Shard>,
+) -> Box<dyn Iterator<Item = WorkUnit<'a>> + Send + 'a> {
+    let merged = move |ids: &'a [String]| {
+        Merge::new(
+            ids.iter()
+                .flat_map(|id| intervals.iter().map(move |interval_period| (id.as_str(), interval_period)))
+                .map(|(id, interval_period)| (id, interval_period, bucket_ends(start, end, interval_period)))
+                .collect(),
+        )
+    };
+    let units: Box<dyn Iterator<Item = WorkUnit<'a>> + Send + 'a> = match order {
+        Order::Id => Box::new(ids.iter().flat_map(move |id| merged(slice::from_ref(id)))),
+        Order::Time => Box::new(merged(ids)),
+    };
+
+    Box::new(units.filter(move |unit| shard.is_none_or(|shard| shard.contains(unit))))
+}
+
+// how many units `schedule` generates for the id, without generating them
+pub fn count_units(
+    id: &str,
+    intervals: &[Interval],
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    shard: Option<Shard>,
+) -> u64 {
+    let count = |start, end| -> u64 {
+        intervals
+            .iter()
+            .map(|interval_period| count_bucket_ends(start, end, interval_period))
+            .sum()
+    };
+    let shard = match shard {
+        Some(shard) => shard,
+        None => return count(start, end),
+    };
+
+    // shards are by the UTC day a unit ends in
+    let mut total = 0;
+    let mut day = start.date();
+    while day.and_hms(0, 0, 0) < end {
+        if shard.contains_day(day, id) {
+            let day_start = day.and_hms(0, 0, 0).max(start);
+            let day_end = (day.and_hms(0, 0, 0) + Duration::days(1)).min(end);
+            total += count(day_start, day_end);
+        }
+        day = day.succ();
+    }
+
+    total
+}
+
+// the number of `bucket_ends`, worked out for fixed intervals
+fn count_bucket_ends(start: NaiveDateTime, end: NaiveDateTime, interval_period: &Interval) -> u64 {
+    match interval_period.kind {
+        IntervalKind::Fixed => {
+            let bucket = interval_period.bucket_secs as i64 * 1_000_000_000;
+            let offset = interval_period.offset_secs as i64 * 1_000_000_000;
+            // boundaries up to `at`, exclusive
+            let before = |at: NaiveDateTime| (at.timestamp_nanos() - offset + bucket - 1).div_euclid(bucket);
+            (before(end) - before(start)).max(0) as u64
+        }
+        IntervalKind::Calendar(..) => bucket_ends(start, end, interval_period).count() as u64,
+    }
+}
+
+// the ends of the buckets a run over [start, end) downsamples: the interval boundaries in that range,
+// each closing the bucket before it
+pub fn bucket_ends(start: NaiveDateTime, end: NaiveDateTime, interval_period: &Interval) -> BucketEnds<'_> {
+    let mut boundary = interval_start(&start, interval_period);
+    if boundary < start {
+        boundary = next_interval_start(&boundary, interval_period);
+    }
+
+    BucketEnds {
+        interval_period,
+        next: boundary,
+        end,
+    }
+}
+
+pub struct BucketEnds<'a> {
+    interval_period: &'a Interval,
+    next: NaiveDateTime,
+    end: NaiveDateTime,
+}
+
+impl Iterator for BucketEnds<'_> {
+    type Item = NaiveDateTime;
+
+    fn next(&mut self) -> Option<NaiveDateTime> {
+        if self.next >= self.end {
+            return None;
+        }
+        let boundary = self.next;
+        self.next = next_interval_start(&boundary, self.interval_period);
+
+        Some(boundary)
+    }
+}
+
+// merges the buckets of several ids and intervals by their end, ties in the order of the streams
+struct Merge<'a> {
+    streams: Vec<(&'a str, &'a Interval, BucketEnds<'a>)>,
+    heads: BinaryHeap<Reverse<(NaiveDateTime, usize)>>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(mut streams: Vec<(&'a str, &'a Interval, BucketEnds<'a>)>) -> Merge<'a> {
+        let heads = streams
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, (_, _, ends))| ends.next().map(|end| Reverse((end, i))))
+            .collect();
+
+        Merge { streams, heads }
+    }
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = WorkUnit<'a>;
+
+    fn next(&mut self) -> Option<WorkUnit<'a>> {
+        let Reverse((end, i)) = self.heads.pop()?;
+        let (id, interval_period, ref mut ends) = self.streams[i];
+        if let Some(next) = ends.next() {
+            self.heads.push(Reverse((next, i)));
+        }
+
+        Some(WorkUnit {
+            id,
+            interval_period,
+            start: interval_start(&(end - Duration::nanoseconds(1)), interval_period),
+            end,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::CalendarUnit;
+
+    fn interval(name: &str, bucket_secs: u64) -> Interval {
+        Interval {
+            name: name.to_owned(),
+            kind: IntervalKind::Fixed,
+            bucket_secs,
+            points_per_bucket: 60,
+            offset_secs: 0,
+        }
+    }
+
+    fn at(secs: i64) -> NaiveDateTime {
+        NaiveDateTime::from_timestamp(secs, 0)
+    }
+
+    #[test]
+    fn test_bucket_ends() {
+        let minute = interval("1m", 60);
+        assert_eq!(bucket_ends(at(30), at(180), &minute).collect::<Vec<_>>(), vec![at(60), at(120)]);
+        assert_eq!(bucket_ends(at(180), at(240), &minute).collect::<Vec<_>>(), vec![at(180)]);
+    }
+
+    #[test]
+    fn test_schedule_matches_per_second_iteration() {
+        let intervals = vec![interval("1m", 60), interval("5m", 300)];
+        let ids = vec!["a".to_owned(), "b".to_owned()];
+        let (start, end) = (at(250), at(1000));
+
+        // what iterating every second and checking every interval produced
+        let mut expected = vec![];
+        for id in ids.iter() {
+            for secs in 250..1000 {
+                for interval_period in intervals.iter() {
+                    if interval_start(&at(secs), interval_period) == at(secs) {
+                        expected.push((id.as_str(), interval_period.name.as_str(), at(secs)));
+                    }
+                }
+            }
+        }
+
+        let units: Vec<WorkUnit> = schedule(&ids, &intervals, start, end, Order::Id, None).collect();
+        let actual: Vec<(&str, &str, NaiveDateTime)> = units
+            .iter()
+            .map(|unit| (unit.id, unit.interval_period.name.as_str(), unit.end))
+            .collect();
+        assert_eq!(actual, expected);
+        assert_eq!(units[0].start, at(240));
+
+        let by_time: Vec<WorkUnit> = schedule(&ids, &intervals, start, end, Order::Time, None).collect();
+        // both intervals of `a` close a bucket at 300
+        assert_eq!(
+            by_time.iter().take(4).map(|unit| unit.id).collect::<Vec<_>>(),
+            vec!["a", "a", "b", "b"]
+        );
+        assert!(by_time.windows(2).all(|pair| pair[0].end <= pair[1].end));
+    }
+
+    #[test]
+    fn test_shards_are_disjoint() {
+        let intervals = vec![interval("1h", 3600)];
+        let ids = vec!["a".to_owned(), "b".to_owned(), "c".to_owned()];
+        let (start, end) = (at(0), at(86400 * 7));
+
+        let all = schedule(&ids, &intervals, start, end, Order::Id, None).count();
+        let sharded: usize = (0..3)
+            .map(|index| {
+                let shard = Shard { index, count: 3 };
+                schedule(&ids, &intervals, start, end, Order::Id, Some(shard)).count()
+            })
+            .sum();
+        assert_eq!(sharded, all);
+    }
+
+    #[test]
+    fn test_count_units() {
+        let mut offset = interval("1h", 3600);
+        offset.offset_secs = 1800;
+        let day = Interval {
+            name: "1d".to_owned(),
+            kind: IntervalKind::Calendar(CalendarUnit::Day, chrono_tz::UTC),
+            bucket_secs: 86400,
+            points_per_bucket: 24,
+            offset_secs: 0,
+        };
+        let intervals = vec![interval("1m", 60), offset, day];
+        let (start, end) = (NaiveDateTime::from_timestamp(90, 500), at(86400 * 3 + 1800));
+
+        for shard in [None, Some(Shard { index: 0, count: 2 }), Some(Shard { index: 1, count: 2 })].iter() {
+            for id in ["a", "b"].iter() {
+                let ids = vec![id.to_string()];
+                let generated = schedule(&ids, &intervals, start, end, Order::Id, *shard).count() as u64;
+                assert_eq!(count_units(id, &intervals, start, end, *shard), generated);
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::settings::Config;
+use failure_derive::Fail;
+use log::{info, warn};
+use regex::Regex;
+
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(
+        display = "Unknown interval passed to `--intervals`: {:?}, configured are {:?}",
+        _0, _1
+    )]
+    UnknownInterval(String, Vec<String>),
+}
+
+// which ids and intervals `downsample`, `split` and `listen` work on. `None` selects everything configured.
+#[derive(Debug, Default)]
+pub struct Selection {
+    // from `--ids` and `--id-file`, these select among `vars.ids`
+    pub ids: Option<Vec<String>>,
+    pub include: Option<Regex>,
+    pub exclude: Option<Regex>,
+    pub intervals: Option<Vec<String>>,
+}
+
+impl Selection {
+    pub fn matches_id(&self, id: &str) -> bool {
+        self.ids.as_ref().is_none_or(|ids| ids.iter().any(|i| i == id))
+            && self.include.as_ref().is_none_or(|re| re.is_match(id))
+            && !self.exclude.as_ref().is_some_and(|re| re.is_match(id))
+    }
+
+    // narrows `vars.ids` and `downsampler.intervals` of every pipeline down to the selected ones, so everything
+    // reading the config afterwards only sees those. `--ids` selects among the ids of each pipeline: an id that
+    // no pipeline has is dropped from the selection with a warning, while an interval that no pipeline has is an error.
+    pub fn apply_all(&mut self, pipelines: &mut [Config]) -> Result<(), Error> {
+        if let Some(ref names) = self.intervals {
+            let mut configured: Vec<String> = pipelines
+                .iter()
+                .flat_map(|config| config.downsampler.intervals.iter())
+                .map(|interval| interval.name.clone())
+                .collect();
+            configured.sort();
+            configured.dedup();
+            if let Some(unknown) = names.iter().find(|name| !configured.contains(name)) {
+                return Err(Error::UnknownInterval(unknown.clone(), configured));
+            }
+        }
+        if let Some(ref mut ids) = self.ids {
+            // also keeps `listen` from taking the updates of the dropped ids
+            ids.retain(|id| {
+                let configured = pipelines.iter().any(|config| config.vars.ids.contains(id));
+                if !configured {
+                    warn!(id = id.as_str(); "Skipping selected id, it isn't in vars.ids");
+                }
+                configured
+            });
+        }
+
+        for config in pipelines.iter_mut() {
+            if let Some(ref names) = self.intervals {
+                config
+                    .downsampler
+                    .intervals
+                    .retain(|interval| names.contains(&interval.name));
+            }
+            config.vars.ids.retain(|id| self.matches_id(id));
+
+            if self.is_narrowing() {
+                info!(
+                    "Selected {} ids and {} intervals{}",
+                    config.vars.ids.len(),
+                    config.downsampler.intervals.len(),
+                    config
+                        .job
+                        .as_ref()
+                        .map_or(String::new(), |job| format!(" of job {}", job))
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_narrowing(&self) -> bool {
+        self.ids.is_some()
+            || self.include.is_some()
+            || self.exclude.is_some()
+            || self.intervals.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::config_from_file;
+
+    #[test]
+    fn test_matches_id() {
+        let selection = Selection {
+            include: Some(Regex::new("^binance_").unwrap()),
+            exclude: Some(Regex::new("USDT$").unwrap()),
+            ..Selection::default()
+        };
+        assert!(selection.matches_id("binance_ETHBTC"));
+        assert!(!selection.matches_id("binance_BTCUSDT"));
+        assert!(!selection.matches_id("bitfinex_ETHBTC"));
+
+        let selection = Selection {
+            ids: Some(vec!["binance_ETHBTC".to_owned()]),
+            ..Selection::default()
+        };
+        assert!(selection.matches_id("binance_ETHBTC"));
+        assert!(!selection.matches_id("binance_BTCUSDT"));
+        assert!(Selection::default().matches_id("anything"));
+    }
+
+    #[test]
+    fn test_ids_select_among_configured() {
+        let mut pipelines = vec![config_from_file("config.toml").unwrap()];
+        let mut selection = Selection {
+            ids: Some(vec!["ADABTC".to_owned(), "NOTCONFIGURED".to_owned()]),
+            ..Selection::default()
+        };
+        selection.apply_all(&mut pipelines).unwrap();
+        assert_eq!(pipelines[0].vars.ids, vec!["ADABTC".to_owned()]);
+        assert!(!selection.matches_id("NOTCONFIGURED"));
+    }
+}
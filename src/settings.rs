@@ -1,8 +1,12 @@
-use config::{Config as Conf, ConfigError, File};
+use chrono_tz::Tz;
+use config::{Config as Conf, ConfigError, Environment, File};
 use humantime::parse_duration;
 use serde::{de::Error as DeserError, Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub enum FieldDataType {
     Float,
     Integer,
@@ -24,7 +28,7 @@ impl FieldDataType {
             "integer" => Ok(FieldDataType::Integer),
             "boolean" => Ok(FieldDataType::Boolean),
             "string" => Ok(FieldDataType::String),
-            val @ _ => Err(DeserError::custom(format!(
+            val => Err(DeserError::custom(format!(
                 "Unrecognized field data type: {:?}",
                 val
             ))),
@@ -32,98 +36,744 @@ impl FieldDataType {
     }
 }
 
-#[derive(Debug, Deserialize)]
+// unit of the timestamps producers write into the updates hash
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimestampUnit {
+    #[default]
+    Auto,
+    Seconds,
+    Millis,
+    Micros,
+    Nanos,
+    Rfc3339,
+}
+
+impl TimestampUnit {
+    fn deserialize_with<'de, D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(de)?;
+        let s = s.to_lowercase();
+
+        match s.as_ref() {
+            "auto" => Ok(TimestampUnit::Auto),
+            "s" => Ok(TimestampUnit::Seconds),
+            "ms" => Ok(TimestampUnit::Millis),
+            "us" => Ok(TimestampUnit::Micros),
+            "ns" => Ok(TimestampUnit::Nanos),
+            "rfc3339" => Ok(TimestampUnit::Rfc3339),
+            val => Err(DeserError::custom(format!(
+                "Unrecognized timestamp unit: {:?}",
+                val
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Field {
     pub name: String,
     #[serde(deserialize_with = "FieldDataType::deserialize_with")]
     pub data_type: FieldDataType,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct InfluxDB {
     pub url: String,
     pub db: String,
     pub retention_policy: String,
     pub username: String,
+    #[serde(default)]
     pub pass: String,
+    // file holding the password, instead of `pass`
+    #[serde(default)]
+    pub pass_file: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+// keeps the password out of logs
+impl fmt::Debug for InfluxDB {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InfluxDB")
+            .field("url", &self.url)
+            .field("db", &self.db)
+            .field("retention_policy", &self.retention_policy)
+            .field("username", &self.username)
+            .field("pass", &"<redacted>")
+            .field("pass_file", &self.pass_file)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Vars {
     pub ids: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalendarUnit {
+    Day,
+    // starting on Monday
+    Week,
+    Month,
+}
+
+impl CalendarUnit {
+    // average length, for ordering intervals
+    fn nominal_secs(self) -> u64 {
+        match self {
+            CalendarUnit::Day => 86_400,
+            CalendarUnit::Week => 7 * 86_400,
+            CalendarUnit::Month => 2_629_746,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum IntervalKind {
+    // buckets of `bucket_secs`, starting at the unix epoch plus the offset
+    Fixed,
+    // calendar days, weeks or months in the time zone, starting at midnight local time plus the offset
+    Calendar(CalendarUnit, Tz),
+}
+
+#[derive(Debug, Clone)]
 pub struct Interval {
+    // output name, the `time_interval` of the measurement template and part of the checkpoint keys
     pub name: String,
-    pub duration_secs: u64,
+    pub kind: IntervalKind,
+    // length of the buckets the series is downsampled in, the average length for calendar intervals
+    pub bucket_secs: u64,
+    // how many points are kept per bucket
+    pub points_per_bucket: usize,
+    // shifts the start of the buckets
+    pub offset_secs: u64,
 }
 
-// convert interval strings into u64 seconds interval periods
-fn deserialize_intervals<'de, D>(de: D) -> Result<Vec<Interval>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let v: Vec<String> = Vec::<String>::deserialize(de)?;
-
-    v.into_iter()
-        .map(|s| {
-            let duration_std = parse_duration(&s).map_err(|e| {
-                DeserError::custom(format!(
-                    "Error parsing interval duration: {:?} ({:?})",
-                    &s, &e
-                ))
-            })?;
-
-            Ok(Interval {
-                duration_secs: duration_std.as_secs() * 60,
-                name: s,
-            })
+// an interval as written in the config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum IntervalSpec {
+    // "1s", only accepted with `legacy_minutes`
+    Legacy(String),
+    // { bucket = "1m", points_per_bucket = 60, offset = "0s", name = "1s" }
+    // or { calendar = "week", timezone = "Europe/Berlin", points_per_bucket = 168, name = "1w" }
+    Explicit {
+        #[serde(default)]
+        bucket: Option<String>,
+        // day, week or month
+        #[serde(default)]
+        calendar: Option<String>,
+        // of calendar intervals, defaults to UTC
+        #[serde(default)]
+        timezone: Option<String>,
+        #[serde(default = "default_points_per_bucket")]
+        points_per_bucket: usize,
+        #[serde(default)]
+        offset: Option<String>,
+        // defaults to `bucket` or `calendar`
+        #[serde(default)]
+        name: Option<String>,
+    },
+}
+
+// the number of points per bucket that used to be hardcoded
+const LEGACY_POINTS_PER_BUCKET: usize = 60;
+
+fn default_points_per_bucket() -> usize {
+    LEGACY_POINTS_PER_BUCKET
+}
+
+fn parse_interval_duration(s: &str) -> Result<u64, ConfigError> {
+    parse_duration(s)
+        .map(|duration| duration.as_secs())
+        .map_err(|e| {
+            ConfigError::Message(format!(
+                "Error parsing interval duration: {:?} ({:?})",
+                s, e
+            ))
+        })
+}
+
+fn parse_calendar_unit(s: &str) -> Result<CalendarUnit, ConfigError> {
+    match s.to_lowercase().as_ref() {
+        "day" => Ok(CalendarUnit::Day),
+        "week" => Ok(CalendarUnit::Week),
+        "month" => Ok(CalendarUnit::Month),
+        val => Err(ConfigError::Message(format!(
+            "Unrecognized calendar unit: {:?}, expected day, week or month",
+            val
+        ))),
+    }
+}
+
+fn resolve_intervals(
+    specs: &[IntervalSpec],
+    legacy_minutes: bool,
+) -> Result<Vec<Interval>, ConfigError> {
+    specs
+        .iter()
+        .map(|spec| {
+            let interval = match spec {
+                // "1s" used to mean a bucket of one minute with 60 points, i.e. 1 point per second on average
+                IntervalSpec::Legacy(s) if legacy_minutes => Interval {
+                    name: s.clone(),
+                    kind: IntervalKind::Fixed,
+                    bucket_secs: parse_interval_duration(s)? * 60,
+                    points_per_bucket: LEGACY_POINTS_PER_BUCKET,
+                    offset_secs: 0,
+                },
+                IntervalSpec::Legacy(s) => {
+                    return Err(ConfigError::Message(format!(
+                        "Interval {:?} is a plain duration, which used to mean a bucket of 60 times that duration. \
+                         Set `legacy_minutes = true` in [downsampler] to keep that meaning, \
+                         or write it as {{ bucket = \"...\", points_per_bucket = ..., name = {:?} }}",
+                        s, s
+                    )))
+                }
+                IntervalSpec::Explicit {
+                    bucket,
+                    calendar,
+                    timezone,
+                    points_per_bucket,
+                    offset,
+                    name,
+                } => {
+                    let (kind, bucket_secs) = match (bucket, calendar, timezone) {
+                        (Some(bucket), None, None) => {
+                            (IntervalKind::Fixed, parse_interval_duration(bucket)?)
+                        }
+                        (None, Some(calendar), timezone) => {
+                            let unit = parse_calendar_unit(calendar)?;
+                            let tz = match timezone {
+                                Some(timezone) => timezone.parse::<Tz>().map_err(|e| {
+                                    ConfigError::Message(format!(
+                                        "Unknown interval time zone: {:?} ({})",
+                                        timezone, e
+                                    ))
+                                })?,
+                                None => Tz::UTC,
+                            };
+                            (IntervalKind::Calendar(unit, tz), unit.nominal_secs())
+                        }
+                        _ => {
+                            return Err(ConfigError::Message(format!(
+                                "Interval {:?} needs either a bucket, or a calendar unit with an optional timezone",
+                                name.as_ref().or(bucket.as_ref()).or(calendar.as_ref())
+                            )))
+                        }
+                    };
+                    Interval {
+                        name: name
+                            .as_ref()
+                            .or(bucket.as_ref())
+                            .or(calendar.as_ref())
+                            .cloned()
+                            .unwrap_or_default(),
+                        kind,
+                        bucket_secs,
+                        points_per_bucket: *points_per_bucket,
+                        offset_secs: match offset {
+                            Some(offset) => parse_interval_duration(offset)?,
+                            None => 0,
+                        },
+                    }
+                }
+            };
+            if interval.bucket_secs == 0 || interval.points_per_bucket == 0 {
+                return Err(ConfigError::Message(format!(
+                    "Interval {:?} needs a bucket of at least 1s and at least 1 point per bucket",
+                    interval.name
+                )));
+            }
+
+            Ok(interval)
         })
         .collect()
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Downsampler {
     pub measurement_template: String,
     pub query_template: String,
     pub x_field_index: usize,
     pub y_field_index: usize,
     pub fields: Vec<Field>,
-    #[serde(deserialize_with = "deserialize_intervals")]
+    // how the points of a bucket are picked
+    #[serde(default, deserialize_with = "Algorithm::deserialize_with")]
+    pub algorithm: Algorithm,
+    // accept plain string intervals, with their old meaning
+    #[serde(default)]
+    pub legacy_minutes: bool,
+    #[serde(rename = "intervals")]
+    interval_specs: Vec<IntervalSpec>,
+    // resolved from `interval_specs` once the config is loaded
+    #[serde(skip)]
     pub intervals: Vec<Interval>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Algorithm {
+    // largest triangle three buckets, keeps the shape of the series
+    #[default]
+    Lttb,
+    // the last point of each of `points_per_bucket` equal time slices of the bucket's points, e.g. for prices
+    // sampled from an order book
+    Last,
+}
+
+impl Algorithm {
+    fn deserialize_with<'de, D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(de)?;
+        let s = s.to_lowercase();
+
+        match s.as_ref() {
+            "lttb" => Ok(Algorithm::Lttb),
+            "last" => Ok(Algorithm::Last),
+            val => Err(DeserError::custom(format!(
+                "Unrecognized downsampling algorithm: {:?}",
+                val
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Splitter {
     pub measurement_template: String,
     pub query_template: String,
     pub fields: Vec<Field>,
+    // `split` runs every id in parallel units of this long, so that busy ids are spread over the threads
+    #[serde(
+        rename = "work_unit",
+        default = "default_work_unit_secs",
+        deserialize_with = "deserialize_duration_secs"
+    )]
+    pub work_unit_secs: u64,
+    // the chunks a work unit is queried in are sized to return about this many points
+    #[serde(default = "default_target_points_per_query")]
+    pub target_points_per_query: usize,
+    #[serde(
+        rename = "min_chunk",
+        default = "default_min_chunk_secs",
+        deserialize_with = "deserialize_duration_secs"
+    )]
+    pub min_chunk_secs: u64,
+}
+
+fn default_work_unit_secs() -> u64 {
+    86_400
+}
+
+fn default_target_points_per_query() -> usize {
+    100_000
+}
+
+fn default_min_chunk_secs() -> u64 {
+    60
+}
+
+// convert a duration string such as "1h" into u64 seconds
+fn deserialize_duration_secs<'de, D>(de: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(de)?;
+    let duration_std = parse_duration(&s)
+        .map_err(|e| DeserError::custom(format!("Error parsing duration: {:?} ({:?})", &s, &e)))?;
+
+    Ok(duration_std.as_secs())
+}
+
+fn deserialize_duration_ms<'de, D>(de: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(de)?;
+    let duration_std = parse_duration(&s)
+        .map_err(|e| DeserError::custom(format!("Error parsing duration: {:?} ({:?})", &s, &e)))?;
+
+    Ok(duration_std.as_millis() as u64)
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Listener {
+    #[serde(default)]
     pub redis_url: String,
+    // file holding the url, instead of `redis_url`, for urls with a password
+    #[serde(default)]
+    pub redis_url_file: Option<String>,
     pub poll_sleep_ms: u64,
     pub measurement_template: String,
     pub query_template: String,
+    // how far behind a checkpoint an update may be and still trigger a recompute, 0 disables
+    #[serde(
+        rename = "allowed_lateness",
+        default,
+        deserialize_with = "deserialize_duration_secs"
+    )]
+    pub allowed_lateness_secs: u64,
+    #[serde(default, deserialize_with = "TimestampUnit::deserialize_with")]
+    pub timestamp_unit: TimestampUnit,
+    // upper bound for the retry delay while Redis or InfluxDB are unavailable
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+    // on startup, downsample everything between the checkpoints and now before handling updates
+    #[serde(default)]
+    pub catchup: bool,
+    // 0 uses one thread per core
+    #[serde(default)]
+    pub catchup_threads: usize,
+    // 0 is unlimited
+    #[serde(default)]
+    pub catchup_max_periods_per_sec: u64,
+    // coordinate with other instances sharing the same Redis
+    #[serde(default)]
+    pub cluster: bool,
+    // defaults to hostname, pid and start time
+    #[serde(default)]
+    pub instance_id: String,
+    // an instance that doesn't heartbeat for this long is considered dead and its ids are rebalanced
+    #[serde(default = "default_lease_ttl_ms")]
+    pub lease_ttl_ms: u64,
+    // a period that failed this many times is moved to the dead letters, 0 retries forever
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    // `/healthz` fails when there were pending updates but no period was processed for this long, 0 disables
+    #[serde(
+        rename = "health_max_idle",
+        default,
+        deserialize_with = "deserialize_duration_secs"
+    )]
+    pub health_max_idle_secs: u64,
+    // `/healthz` fails when a checkpoint is this far behind the latest update of its id, 0 disables
+    #[serde(
+        rename = "health_max_checkpoint_lag",
+        default,
+        deserialize_with = "deserialize_duration_secs"
+    )]
+    pub health_max_checkpoint_lag_secs: u64,
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_lease_ttl_ms() -> u64 {
+    10_000
+}
+
+fn default_max_backoff_ms() -> u64 {
+    30_000
+}
+
+// the load put on InfluxDB, shared by all commands. 0 is unlimited
+#[derive(Debug, Clone, Deserialize)]
+pub struct Limits {
+    // threads of `downsample` and `split`, 0 uses one per core
+    #[serde(default)]
+    pub workers: usize,
+    // queries per second across all threads
+    #[serde(default)]
+    pub max_queries_per_sec: u64,
+    // points written per second across all threads
+    #[serde(default)]
+    pub max_points_per_sec: u64,
+    // lowers the query rate below `max_queries_per_sec` while queries take longer than the target latency
+    #[serde(default)]
+    pub adaptive: bool,
+    #[serde(
+        rename = "adaptive_target_latency",
+        default = "default_adaptive_target_latency_ms",
+        deserialize_with = "deserialize_duration_ms"
+    )]
+    pub adaptive_target_latency_ms: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            workers: 0,
+            max_queries_per_sec: 0,
+            max_points_per_sec: 0,
+            adaptive: false,
+            adaptive_target_latency_ms: default_adaptive_target_latency_ms(),
+        }
+    }
+}
+
+fn default_adaptive_target_latency_ms() -> u64 {
+    500
+}
+
+// `backfill` jobs and the leases workers hold on them
+#[derive(Debug, Clone, Deserialize)]
+pub struct Backfill {
+    // the time range of one job, per id and interval
+    #[serde(
+        rename = "chunk",
+        default = "default_backfill_chunk_secs",
+        deserialize_with = "deserialize_duration_secs"
+    )]
+    pub chunk_secs: u64,
+    // a worker that doesn't renew the lease of its job for this long is considered gone, the job is handed out again
+    #[serde(
+        rename = "lease_ttl",
+        default = "default_backfill_lease_ttl_ms",
+        deserialize_with = "deserialize_duration_ms"
+    )]
+    pub lease_ttl_ms: u64,
+    // a job that failed this many times is set aside as failed
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+impl Default for Backfill {
+    fn default() -> Backfill {
+        Backfill {
+            chunk_secs: default_backfill_chunk_secs(),
+            lease_ttl_ms: default_backfill_lease_ttl_ms(),
+            max_attempts: default_max_attempts(),
+        }
+    }
+}
+
+fn default_backfill_chunk_secs() -> u64 {
+    86_400
+}
+
+fn default_backfill_lease_ttl_ms() -> u64 {
+    60_000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Metrics {
+    // address of the Prometheus endpoint e.g "0.0.0.0:9100"
+    pub addr: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    fn deserialize_with<'de, D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(de)?;
+        let s = s.to_lowercase();
+
+        match s.as_ref() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            val => Err(DeserError::custom(format!(
+                "Unrecognized log format: {:?}",
+                val
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Log {
+    #[serde(default, deserialize_with = "LogFormat::deserialize_with")]
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub influxdb: InfluxDB,
     pub vars: Vars,
     pub downsampler: Downsampler,
     pub splitter: Splitter,
     pub listen: Listener,
+    // `listen` serves metrics when this is set, `downsample` and `split` only with `--metrics`
+    #[serde(default)]
+    pub metrics: Option<Metrics>,
+    // the level is set with `RUST_LOG`
+    #[serde(default)]
+    pub log: Log,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub backfill: Backfill,
+    // named pipelines, see `pipelines`
+    #[serde(default)]
+    pub jobs: Vec<Job>,
+    // the job this config was resolved for, `None` for the top-level sections
+    #[serde(skip)]
+    pub job: Option<String>,
 }
 
+// a named pipeline. the sections it leaves out are taken from the top level
+#[derive(Debug, Clone, Deserialize)]
+pub struct Job {
+    pub name: String,
+    #[serde(default)]
+    pub ids: Option<Vec<String>>,
+    #[serde(default)]
+    pub downsampler: Option<Downsampler>,
+    #[serde(default)]
+    pub splitter: Option<Splitter>,
+    #[serde(default)]
+    pub listen: Option<JobListener>,
+}
+
+// the templates `listen` uses for a job, the rest of `[listen]` is shared by all jobs
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobListener {
+    pub measurement_template: String,
+    pub query_template: String,
+}
+
+impl Config {
+    // the configs to run with: the job `name`, or else every job, or the top-level sections if there are no jobs
+    pub fn pipelines(&self, name: Option<&str>) -> Result<Vec<Config>, ConfigError> {
+        match name {
+            Some(name) => match self.jobs.iter().find(|job| job.name == name) {
+                Some(job) => Ok(vec![self.for_job(job)]),
+                None => Err(ConfigError::Message(format!(
+                    "Unknown job {:?}, configured are {:?}",
+                    name,
+                    self.job_names()
+                ))),
+            },
+            None if self.jobs.is_empty() => Ok(vec![self.clone()]),
+            None => Ok(self.jobs.iter().map(|job| self.for_job(job)).collect()),
+        }
+    }
+
+    pub fn job_names(&self) -> Vec<&str> {
+        self.jobs.iter().map(|job| job.name.as_str()).collect()
+    }
+
+    fn for_job(&self, job: &Job) -> Config {
+        let mut config = self.clone();
+        config.jobs = vec![];
+        config.job = Some(job.name.clone());
+        if let Some(ref ids) = job.ids {
+            config.vars.ids = ids.clone();
+        }
+        if let Some(ref downsampler) = job.downsampler {
+            config.downsampler = downsampler.clone();
+        }
+        if let Some(ref splitter) = job.splitter {
+            config.splitter = splitter.clone();
+        }
+        if let Some(ref listen) = job.listen {
+            config.listen.measurement_template = listen.measurement_template.clone();
+            config.listen.query_template = listen.query_template.clone();
+        }
+
+        config
+    }
+
+    // the `job` label of the metrics, empty without jobs
+    pub fn job_label(&self) -> &str {
+        self.job.as_deref().unwrap_or("")
+    }
+
+    // the Redis key `name` of the job, so that jobs don't share checkpoints, updates or queues.
+    // the top-level sections keep the plain keys.
+    pub fn redis_key(&self, name: &str) -> String {
+        match self.job {
+            Some(ref job) => format!("{}:{}", name, job),
+            None => name.to_owned(),
+        }
+    }
+}
+
+// prefix of the environment variables that override the config file,
+// e.g. DOWNSAMPLER_INFLUXDB__PASS overrides `pass` in `[influxdb]`
+const ENV_PREFIX: &str = "DOWNSAMPLER";
+
 pub fn config_from_file(filename: &str) -> Result<Config, ConfigError> {
     let mut settings = Conf::default();
-    settings.merge(File::with_name(filename)).unwrap();
+    settings.merge(File::with_name(filename))?;
+    settings.merge(Environment::with_prefix(ENV_PREFIX).separator("__"))?;
+
+    let mut config: Config = settings.try_into()?;
+    config.downsampler.intervals = resolve_intervals(
+        &config.downsampler.interval_specs,
+        config.downsampler.legacy_minutes,
+    )?;
+    let mut names = HashSet::new();
+    for (i, job) in config.jobs.iter_mut().enumerate() {
+        if job.name.is_empty() {
+            return Err(ConfigError::Message(format!("jobs[{}] needs a name", i)));
+        }
+        if !names.insert(job.name.clone()) {
+            return Err(ConfigError::Message(format!("The job name {:?} is used twice", job.name)));
+        }
+        if let Some(ref mut downsampler) = job.downsampler {
+            downsampler.intervals =
+                resolve_intervals(&downsampler.interval_specs, downsampler.legacy_minutes)?;
+        }
+        if let Some(ref splitter) = job.splitter {
+            check_splitter(splitter)?;
+        }
+    }
+    config.influxdb.pass = read_secret(
+        "influxdb.pass",
+        &config.influxdb.pass,
+        &config.influxdb.pass_file,
+    )?;
+    config.listen.redis_url = read_secret(
+        "listen.redis_url",
+        &config.listen.redis_url,
+        &config.listen.redis_url_file,
+    )?;
+    if config.listen.redis_url.is_empty() {
+        return Err(ConfigError::NotFound("listen.redis_url".to_owned()));
+    }
+    if config.limits.adaptive && config.limits.max_queries_per_sec == 0 {
+        return Err(ConfigError::Message(
+            "limits.adaptive needs limits.max_queries_per_sec to start from".to_owned(),
+        ));
+    }
+    if config.backfill.chunk_secs == 0 || config.backfill.lease_ttl_ms == 0 {
+        return Err(ConfigError::Message(
+            "backfill.chunk and backfill.lease_ttl must be above 0".to_owned(),
+        ));
+    }
+    check_splitter(&config.splitter)?;
+
+    Ok(config)
+}
 
-    // Print out our settings (as a HashMap)
-    println!("{:?}", settings.clone().try_into::<Config>().unwrap());
+fn check_splitter(splitter: &Splitter) -> Result<(), ConfigError> {
+    if splitter.min_chunk_secs == 0
+        || splitter.min_chunk_secs > splitter.work_unit_secs
+        || splitter.target_points_per_query == 0
+    {
+        return Err(ConfigError::Message(
+            "splitter.min_chunk, work_unit and target_points_per_query must be above 0, min_chunk at most work_unit"
+                .to_owned(),
+        ));
+    }
 
-    settings.try_into()
+    Ok(())
+}
+
+// the value of a setting that can also be read from a file, only one of them may be set
+fn read_secret(key: &str, value: &str, file: &Option<String>) -> Result<String, ConfigError> {
+    match file {
+        None => Ok(value.to_owned()),
+        Some(_) if !value.is_empty() => Err(ConfigError::Message(format!(
+            "{} and {}_file are both set, use one of them",
+            key, key
+        ))),
+        Some(path) => fs::read_to_string(path)
+            .map(|secret| secret.trim_end_matches(&['\r', '\n'][..]).to_owned())
+            .map_err(|e| {
+                ConfigError::Message(format!("Failed to read {}_file {:?}: {}", key, path, e))
+            }),
+    }
 }
@@ -1,24 +1,32 @@
 use chrono::NaiveDateTime;
-use crate::cmdargs::TimePeriod;
+use crate::chunk_size::ChunkSizer;
+use crate::cmdargs::BatchArgs;
 use crate::influx::from_json_values;
 use crate::influx::to_point;
 use crate::influx::FieldValue;
-use crate::influx::{get_range, influx_client, save_points, Error};
+use crate::influx::{get_range, influx_client, save_points, Error as InfluxError};
+use crate::metrics;
+use crate::progress::{Outcome, Progress};
 use crate::settings::Config;
 use crate::settings::Field;
-use crate::utils::error::print_err_and_exit;
-use crate::utils::time::intervals;
-use influx_db_client::Point;
+use crate::utils::error::{Context, Error};
+use crate::utils::shutdown;
+use influx_db_client::{Client, Point};
+use log::debug;
 use rayon::prelude::*;
 use std::collections::HashMap;
 use string_template::Template;
 use time::Duration;
 
-//#[derive(Fail, Debug)]
-//pub enum Error {
-//}
+// `split` works on the time range of an id in units of `splitter.work_unit`, which run in parallel
+#[derive(Debug, Clone, Copy)]
+pub struct SplitUnit<'a> {
+    pub id: &'a str,
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+}
 
-pub fn split(args: &TimePeriod, config: &Config) -> () {
+pub fn split(args: &BatchArgs, config: &Config) -> Outcome {
     let client = influx_client(
         &config.influxdb.url,
         &config.influxdb.db,
@@ -28,62 +36,136 @@ pub fn split(args: &TimePeriod, config: &Config) -> () {
 
     let measurement_template = Template::new(&config.splitter.measurement_template);
     let query_template = Template::new(&config.splitter.query_template);
-
-    // Hey look, par_iter() !!
-    config
-        .vars
-        .ids
-        .par_iter()
-        //        .take(1)
-        .for_each(|id| {
-            println!("start {}", id);
-
-            let measurement_name = make_measurement_name(&measurement_template, id);
-
-            for (start, end)
-//                (_i, (start, end))
-                in
-                intervals(args.start, args.end, Duration::hours(1))
-//                .enumerate()
-//                .take(1)
-                {
-                    let query_str = build_query(&query_template, id, start, end, 0);
-                    let series = match get_range(&client, &query_str) {
-                        Ok(series) => series,
-                        Err(err) => match err {
-                            Error::NoResult => continue,
-                            e => print_err_and_exit(e)
-                        },
-                    };
-
-                    let _count = series.values.iter().count();
-
-//                    println!("{} - [{} - {}] ({})", i, start, end, count);
-
-                    let vals = from_json_values(&series.values, &config.splitter.fields)
-                        .unwrap_or_else(|e| print_err_and_exit(e));
-
-                    let points = to_points(&vals, &measurement_name, &config.splitter.fields);
-
-//                println!("{:#?}", points);
-
-                    // TODO: handle errors
-                    save_points(&client, &config.influxdb.retention_policy, points).unwrap();
-//                        {
-//                        Err(e) => {
-//
-//                        }
-//                        _ => continue
-//                    }
+    let progress = Progress::new("split", &config.vars.ids, &args.period);
+    let sizer = ChunkSizer::new(&config.splitter);
+    let units = split_units(
+        &config.vars.ids,
+        args.period.start,
+        args.period.end,
+        Duration::seconds(config.splitter.work_unit_secs as i64),
+    );
+    for unit in units.iter() {
+        progress.expect_units(unit.id, 1);
+    }
+
+    // Hey look, par_iter() !! rayon steals units from busy threads, so the units of a busy id are spread out
+    let stopped: Vec<Option<(&str, NaiveDateTime)>> = progress.run(|| {
+        units
+            .par_iter()
+            .map(|unit| {
+                // the other units of a failed id are skipped
+                if progress.stopping() || progress.has_failed(unit.id) {
+                    return Some((unit.id, unit.start));
                 }
-            println!("end {}", id);
-        });
+                progress.start_id(unit.id);
+
+                let measurement_name = make_measurement_name(&measurement_template, unit.id);
+                match split_unit(config, &client, &query_template, &measurement_name, unit, &sizer, &progress) {
+                    Ok(None) => {
+                        progress.finish_unit(unit.id, unit.end);
+                        None
+                    }
+                    Ok(Some(start)) => {
+                        debug!(id = unit.id; "interrupted");
+                        Some((unit.id, start))
+                    }
+                    Err(e) => {
+                        progress.record_failure(unit.id, &e, args.on_error);
+                        None
+                    }
+                }
+            })
+            .collect()
+    });
+
+    let outcome = progress.finish(args.report.as_deref());
+    let interrupted = progress.resume_points(stopped.into_iter().flatten());
+    if !interrupted.is_empty() {
+        shutdown::print_progress(&interrupted);
+    }
+
+    outcome
+}
+
+// the units of every id, `unit` long except for the last one of each id which ends at `end`
+pub fn split_units(ids: &[String], start: NaiveDateTime, end: NaiveDateTime, unit: Duration) -> Vec<SplitUnit<'_>> {
+    let mut units = vec![];
+    for id in ids.iter() {
+        let mut unit_start = start;
+        while unit_start < end {
+            let unit_end = (unit_start + unit).min(end);
+            units.push(SplitUnit {
+                id,
+                start: unit_start,
+                end: unit_end,
+            });
+            unit_start = unit_end;
+        }
+    }
+
+    units
+}
+
+// splits `unit` in chunks sized by `sizer`, returns where it stopped if it was interrupted
+fn split_unit(
+    config: &Config,
+    client: &Client,
+    query_template: &Template,
+    measurement_name: &str,
+    unit: &SplitUnit,
+    sizer: &ChunkSizer,
+    progress: &Progress,
+) -> Result<Option<NaiveDateTime>, Error> {
+    let id = unit.id;
+    let mut start = unit.start;
+    while start < unit.end {
+        if progress.stopping() {
+            // everything before `start` has been written
+            return Ok(Some(start));
+        }
+        let end = (start + sizer.next(id)).min(unit.end);
+        let context = || Context::new(id, None, start);
+
+        let query_str = build_query(query_template, id, start, end, 0);
+        let series = match get_range(client, &query_str) {
+            Ok(series) => series,
+            Err(InfluxError::NoResult) => {
+                sizer.observe(id, end - start, 0);
+                progress.record_query(id, 0, 0);
+                start = end;
+                continue;
+            }
+            Err(e) => return Err(Error::influx(context(), e)),
+        };
+
+        let count = series.values.len();
+        sizer.observe(id, end - start, count);
+        // a split has no interval
+        metrics::POINTS_READ
+            .with_label_values(&[config.job_label(), id, "", "split"])
+            .inc_by(count as u64);
+
+        let vals = from_json_values(&series.values, &config.splitter.fields)
+            .map_err(|e| Error::influx(context(), e))?;
+
+        let points = to_points(&vals, measurement_name, &config.splitter.fields);
+        let points_out = points.len();
+        save_points(client, &config.influxdb.retention_policy, points)
+            .map_err(|e| Error::influx(context(), InfluxError::InfluxDbAccessError(e)))?;
+        metrics::POINTS_WRITTEN
+            .with_label_values(&[config.job_label(), id, "", "split"])
+            .inc_by(points_out as u64);
+        progress.record_query(id, count, points_out);
+        start = end;
+    }
+
+    Ok(None)
 }
 
 pub fn to_points(
-    vals: &Vec<Vec<FieldValue>>,
+    vals: &[Vec<FieldValue>],
     measurement: &str,
-    fields: &Vec<Field>,
+    fields: &[Field],
 ) -> Vec<Point> {
     vals.iter()
         .map(|record| to_point(record, measurement, fields))
@@ -0,0 +1,121 @@
+use crate::metrics;
+use crate::settings::Limits;
+use crate::utils::rate_limit::RateLimiter;
+use lazy_static::lazy_static;
+use log::info;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// limits on the load put on InfluxDB, shared by every command and thread through `get_range` and `save_points`.
+// everything is unlimited until `init` is called.
+
+// how often the adaptive mode changes the query rate at most
+const ADAPTIVE_ADJUST_INTERVAL: Duration = Duration::from_secs(1);
+// weight of the latest query in the average latency
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+lazy_static! {
+    static ref QUERIES: RateLimiter = RateLimiter::new(0);
+    static ref POINTS: RateLimiter = RateLimiter::new(0);
+    static ref ADAPTIVE: Mutex<Option<Adaptive>> = Mutex::new(None);
+}
+
+// lowers the query rate while InfluxDB is slow, halving it every second the average latency is above the
+// target and raising it back by a twentieth of the maximum every second it's below
+struct Adaptive {
+    max_per_sec: u64,
+    per_sec: u64,
+    target_secs: f64,
+    // exponentially weighted moving average, in seconds
+    latency_secs: Option<f64>,
+    last_adjusted: Instant,
+}
+
+impl Adaptive {
+    // the rate the average latency calls for
+    fn adjust(&mut self, latency_secs: f64) -> u64 {
+        let average = match self.latency_secs {
+            Some(average) => average * (1.0 - LATENCY_SMOOTHING) + latency_secs * LATENCY_SMOOTHING,
+            None => latency_secs,
+        };
+        self.latency_secs = Some(average);
+        if self.last_adjusted.elapsed() < ADAPTIVE_ADJUST_INTERVAL {
+            return self.per_sec;
+        }
+        self.last_adjusted = Instant::now();
+
+        self.per_sec = if average > self.target_secs {
+            (self.per_sec / 2).max(1)
+        } else {
+            (self.per_sec + (self.max_per_sec / 20).max(1)).min(self.max_per_sec)
+        };
+        self.per_sec
+    }
+}
+
+pub fn init(limits: &Limits) {
+    QUERIES.set_rate(limits.max_queries_per_sec);
+    POINTS.set_rate(limits.max_points_per_sec);
+    metrics::QUERY_RATE_LIMIT.set(limits.max_queries_per_sec as i64);
+
+    if limits.adaptive {
+        *ADAPTIVE.lock().unwrap() = Some(Adaptive {
+            max_per_sec: limits.max_queries_per_sec,
+            per_sec: limits.max_queries_per_sec,
+            target_secs: limits.adaptive_target_latency_ms as f64 / 1000.0,
+            latency_secs: None,
+            last_adjusted: Instant::now(),
+        });
+    }
+}
+
+pub fn before_query() {
+    QUERIES.acquire();
+}
+
+pub fn before_write(points: usize) {
+    POINTS.acquire_n(points as u64);
+}
+
+pub fn record_query_latency(latency: Duration) {
+    let mut adaptive = ADAPTIVE.lock().unwrap();
+    let adaptive = match adaptive.as_mut() {
+        Some(adaptive) => adaptive,
+        None => return,
+    };
+
+    let previous = adaptive.per_sec;
+    let per_sec = adaptive.adjust(latency.as_secs_f64());
+    if per_sec != previous {
+        QUERIES.set_rate(per_sec);
+        metrics::QUERY_RATE_LIMIT.set(per_sec as i64);
+        info!(
+            "Query rate limit changed to {}/s, average latency {:.0}ms",
+            per_sec,
+            adaptive.latency_secs.unwrap_or(0.0) * 1000.0
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_adjust() {
+        let mut adaptive = Adaptive {
+            max_per_sec: 100,
+            per_sec: 100,
+            target_secs: 0.5,
+            latency_secs: None,
+            last_adjusted: Instant::now() - ADAPTIVE_ADJUST_INTERVAL,
+        };
+        assert_eq!(adaptive.adjust(2.0), 50);
+        // adjusted at most once per interval
+        assert_eq!(adaptive.adjust(2.0), 50);
+
+        adaptive.last_adjusted -= ADAPTIVE_ADJUST_INTERVAL;
+        adaptive.latency_secs = Some(0.1);
+        assert_eq!(adaptive.adjust(0.1), 55);
+    }
+}
@@ -1,7 +1,73 @@
+use chrono::NaiveDateTime;
+use crate::{influx, listen};
 use failure::Fail;
+use log::error;
+use std::fmt;
 use std::process::exit;
 
+// the errors `downsample`, `split`, `listen` and `backfill` return instead of exiting, so a failing id doesn't take the others down
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "InfluxDB request failed for {}: {}", _0, _1)]
+    InfluxDb(Context, #[cause] influx::Error),
+    #[fail(display = "Unexpected values returned for {}: {}", _0, _1)]
+    Values(Context, #[cause] influx::Error),
+    #[fail(display = "Listener failed for {}: {}", _0, _1)]
+    Listen(Box<Context>, #[cause] listen::Error),
+    // a listener failure that isn't about a single id, such as Redis being down
+    #[fail(display = "{}", _0)]
+    Listener(#[cause] listen::Error),
+    #[fail(display = "The interval of {} isn't configured", _0)]
+    UnknownInterval(Context),
+}
+
+// what was being processed when an error happened
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub id: String,
+    pub interval: Option<String>,
+    pub period: Option<NaiveDateTime>,
+}
+
+impl Context {
+    pub fn new(id: &str, interval: Option<&str>, period: NaiveDateTime) -> Context {
+        Context {
+            id: id.to_owned(),
+            interval: interval.map(|interval| interval.to_owned()),
+            period: Some(period),
+        }
+    }
+}
+
+impl Error {
+    // requests that failed are told apart from data we can't handle
+    pub fn influx(context: Context, e: influx::Error) -> Error {
+        match e {
+            influx::Error::InfluxDbAccessError(_) => Error::InfluxDb(context, e),
+            _ => Error::Values(context, e),
+        }
+    }
+
+    // the context is boxed, as the listener errors are the largest
+    pub fn listen(context: Context, e: listen::Error) -> Error {
+        Error::Listen(Box::new(context), e)
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if let Some(ref interval) = self.interval {
+            write!(f, " {}", interval)?;
+        }
+        if let Some(period) = self.period {
+            write!(f, " at {}", period)?;
+        }
+        Ok(())
+    }
+}
+
 pub fn print_err_and_exit<T: Fail>(e: T) -> ! {
-    println!("\n\nError: {:?}", e);
+    error!("{:?}", e);
     exit(-1)
 }
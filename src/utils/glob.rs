@@ -0,0 +1,51 @@
+// matches `text` against a shell style pattern, where `*` matches any run of characters and `?` a single one
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // where to resume if the segment after the last `*` doesn't match
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "BTCUSDT"));
+        assert!(glob_match("BTCUSDT", "BTCUSDT"));
+        assert!(glob_match("*USDT", "BTCUSDT"));
+        assert!(glob_match("BTC*", "BTCUSDT"));
+        assert!(glob_match("*C*S*", "BTCUSDT"));
+        assert!(glob_match("ETH???", "ETHBTC"));
+        assert!(!glob_match("ETH???", "ETHUSDT"));
+        assert!(!glob_match("*USDT", "USDTBTC"));
+        assert!(!glob_match("", "BTC"));
+    }
+}
@@ -0,0 +1,74 @@
+use chrono::Utc;
+use crate::settings::LogFormat;
+use env_logger::{Builder, Env};
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+use serde_json::{Map, Value as JsonValue};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// set once the config is loaded, everything logged before that is text
+static JSON: AtomicBool = AtomicBool::new(false);
+
+// logs at `info` unless `RUST_LOG` says otherwise. the key-values of a record (id, interval, period)
+// are appended as `key=value` in text, and become fields of the object in JSON.
+pub fn init() {
+    Builder::from_env(Env::default().default_filter_or("info"))
+        .format(|buf, record| {
+            let fields = fields(record);
+            if JSON.load(Ordering::Relaxed) {
+                write_json(buf, record, fields)
+            } else {
+                write_text(buf, record, fields)
+            }
+        })
+        .init();
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+fn write_text(out: &mut dyn Write, record: &Record, fields: Vec<(String, String)>) -> io::Result<()> {
+    write!(
+        out,
+        "{} {:<5} {}",
+        Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        record.level(),
+        record.args()
+    )?;
+    for (key, value) in fields.iter() {
+        write!(out, " {}={}", key, value)?;
+    }
+    writeln!(out)
+}
+
+fn write_json(out: &mut dyn Write, record: &Record, fields: Vec<(String, String)>) -> io::Result<()> {
+    let mut object = Map::new();
+    object.insert(
+        "time".to_owned(),
+        JsonValue::String(Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()),
+    );
+    object.insert("level".to_owned(), JsonValue::String(record.level().to_string()));
+    object.insert("target".to_owned(), JsonValue::String(record.target().to_owned()));
+    object.insert("message".to_owned(), JsonValue::String(record.args().to_string()));
+    for (key, value) in fields {
+        object.insert(key, JsonValue::String(value));
+    }
+    writeln!(out, "{}", JsonValue::Object(object))
+}
+
+fn fields(record: &Record) -> Vec<(String, String)> {
+    struct Collect(Vec<(String, String)>);
+
+    impl<'kvs> VisitSource<'kvs> for Collect {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            self.0.push((key.to_string(), value.to_string()));
+            Ok(())
+        }
+    }
+
+    let mut collect = Collect(vec![]);
+    let _ = record.key_values().visit(&mut collect);
+    collect.0
+}
@@ -1,2 +1,6 @@
 pub mod error;
+pub mod glob;
+pub mod logging;
+pub mod rate_limit;
+pub mod shutdown;
 pub mod time;
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// spaces out calls evenly so that at most `per_sec` go through each second, shared between threads
+pub struct RateLimiter {
+    // time per call in nanoseconds, 0 is unlimited
+    interval_nanos: AtomicU64,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    // a rate of 0 means unlimited
+    pub fn new(per_sec: u64) -> RateLimiter {
+        let limiter = RateLimiter {
+            interval_nanos: AtomicU64::new(0),
+            next: Mutex::new(Instant::now()),
+        };
+        limiter.set_rate(per_sec);
+        limiter
+    }
+
+    // takes effect from the next call on
+    pub fn set_rate(&self, per_sec: u64) {
+        let interval_nanos = match per_sec {
+            0 => 0,
+            n => 1_000_000_000 / n,
+        };
+        self.interval_nanos.store(interval_nanos, Ordering::Relaxed);
+    }
+
+    // blocks until the caller may proceed
+    pub fn acquire(&self) {
+        self.acquire_n(1)
+    }
+
+    // blocks until the caller may proceed with `n` units, e.g. points of a write
+    pub fn acquire_n(&self, n: u64) {
+        let interval = match self.interval_nanos.load(Ordering::Relaxed) {
+            0 => return,
+            nanos => Duration::from_nanos(nanos.saturating_mul(n)),
+        };
+
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + interval;
+            slot
+        };
+
+        let now = Instant::now();
+        if slot > now {
+            thread::sleep(slot - now);
+        }
+    }
+}
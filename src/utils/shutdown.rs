@@ -0,0 +1,60 @@
+use chrono::NaiveDateTime;
+use lazy_static::lazy_static;
+use log::warn;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref REQUESTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+// SIGINT/SIGTERM only raise a flag that long running loops check between units of work.
+// a second signal while shutting down exits right away.
+pub fn install_handlers() -> Result<(), io::Error> {
+    for signal in &[SIGINT, SIGTERM] {
+        // registered first, so it only fires if the flag was already set by a previous signal
+        flag::register_conditional_shutdown(*signal, 130, Arc::clone(&REQUESTED))?;
+        flag::register(*signal, Arc::clone(&REQUESTED))?;
+    }
+
+    Ok(())
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+// sleeps for `duration` but wakes up early once shutdown is requested
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    let slice = Duration::from_millis(100);
+
+    while !requested() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        thread::sleep(slice.min(deadline - now));
+    }
+}
+
+// prints where each interrupted id stopped and how to resume the batch run.
+// `progress` holds, per id, the time up to which everything has been written.
+pub fn print_progress(progress: &[(&str, NaiveDateTime)]) {
+    warn!("Interrupted, progress was flushed up to:");
+    for (id, done) in progress.iter() {
+        warn!(id = *id; "{}", done);
+    }
+
+    if let Some(resume) = progress.iter().map(|(_, done)| done).min() {
+        warn!(
+            "Resume with: --start '{}'",
+            resume.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+}
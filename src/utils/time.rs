@@ -1,7 +1,16 @@
-use chrono::{Datelike, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono::{DateTime, Datelike, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use crate::settings::{CalendarUnit, Interval, IntervalKind, TimestampUnit};
+use failure_derive::Fail;
 use lazy_static::lazy_static;
 use time::Duration;
 
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "Malformed timestamp: {:?}, expected unit: {:?}", _0, _1)]
+    MalformedTimestamp(String, TimestampUnit),
+}
+
 lazy_static! {
     pub static ref UNIX_EPOCH: NaiveDateTime = Utc.timestamp(0, 0).naive_utc();
 }
@@ -12,24 +21,74 @@ pub fn truncate_seconds(dt: NaiveDateTime) -> NaiveDateTime {
         .naive_utc()
 }
 
-pub fn parse_timestamp_sec(timestamp_str: &str) -> NaiveDateTime {
-    let timestamp = timestamp_str.parse::<i64>().unwrap();
-    NaiveDateTime::from_timestamp(timestamp, 0)
+// parses an epoch timestamp in the given unit or an RFC3339 datetime.
+// `TimestampUnit::Auto` guesses the epoch unit from the magnitude of the number,
+// which is unambiguous for any date between 1973 and 5138.
+pub fn parse_timestamp(timestamp_str: &str, unit: TimestampUnit) -> Result<NaiveDateTime, Error> {
+    let malformed = || Error::MalformedTimestamp(timestamp_str.to_owned(), unit);
+    let timestamp_str = timestamp_str.trim();
+
+    let unit = match (unit, timestamp_str.parse::<i64>()) {
+        (TimestampUnit::Rfc3339, _) | (TimestampUnit::Auto, Err(_)) => {
+            return DateTime::parse_from_rfc3339(timestamp_str)
+                .map(|dt| dt.naive_utc())
+                .map_err(|_| malformed());
+        }
+        (TimestampUnit::Auto, Ok(ts)) => match ts.abs() {
+            ts if ts < 100_000_000_000 => TimestampUnit::Seconds,
+            ts if ts < 100_000_000_000_000 => TimestampUnit::Millis,
+            ts if ts < 100_000_000_000_000_000 => TimestampUnit::Micros,
+            _ => TimestampUnit::Nanos,
+        },
+        (unit, _) => unit,
+    };
+
+    let ts = timestamp_str.parse::<i64>().map_err(|_| malformed())?;
+    let (secs, nanos) = match unit {
+        TimestampUnit::Seconds => (ts, 0),
+        TimestampUnit::Millis => (ts.div_euclid(1_000), ts.rem_euclid(1_000) * 1_000_000),
+        TimestampUnit::Micros => (ts.div_euclid(1_000_000), ts.rem_euclid(1_000_000) * 1_000),
+        _ => (
+            ts.div_euclid(1_000_000_000),
+            ts.rem_euclid(1_000_000_000),
+        ),
+    };
+
+    NaiveDateTime::from_timestamp_opt(secs, nanos as u32).ok_or_else(malformed)
+}
+
+pub fn from_timestamp_nanos(ts: i64) -> NaiveDateTime {
+    NaiveDateTime::from_timestamp(
+        ts.div_euclid(1_000_000_000),
+        ts.rem_euclid(1_000_000_000) as u32,
+    )
 }
 
-pub struct IntervalIterator {
+// inverse of `parse_timestamp`, `TimestampUnit::Auto` formats as nanoseconds
+pub fn format_timestamp(ts: &NaiveDateTime, unit: TimestampUnit) -> String {
+    match unit {
+        TimestampUnit::Seconds => ts.timestamp().to_string(),
+        TimestampUnit::Millis => ts.timestamp_millis().to_string(),
+        TimestampUnit::Micros => (ts.timestamp_nanos() / 1_000).to_string(),
+        TimestampUnit::Rfc3339 => DateTime::<Utc>::from_utc(*ts, Utc).to_rfc3339(),
+        TimestampUnit::Auto | TimestampUnit::Nanos => ts.timestamp_nanos().to_string(),
+    }
+}
+
+pub struct IntervalIterator<'a> {
     pub end: NaiveDateTime,
     pub cur: NaiveDateTime,
     pub prev: NaiveDateTime,
-    pub step: Duration,
+    // the start of the interval following the one starting at the given time
+    step: Box<dyn Fn(&NaiveDateTime) -> NaiveDateTime + 'a>,
 }
 
-impl Iterator for IntervalIterator {
+impl<'a> Iterator for IntervalIterator<'a> {
     type Item = (NaiveDateTime, NaiveDateTime);
 
     fn next(&mut self) -> Option<Self::Item> {
         self.prev = self.cur;
-        self.cur = self.cur + self.step;
+        self.cur = (self.step)(&self.cur);
 
         if self.cur <= self.end {
             Some((self.prev, self.cur))
@@ -39,12 +98,95 @@ impl Iterator for IntervalIterator {
     }
 }
 
-pub fn intervals(start: NaiveDateTime, end: NaiveDateTime, step: Duration) -> IntervalIterator {
+// the buckets of the interval between `start`, which should be the start of a bucket, and `end`
+pub fn interval_buckets(
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    interval_period: &Interval,
+) -> IntervalIterator<'_> {
     IntervalIterator {
         end,
         cur: start,
         prev: start,
-        step,
+        step: Box::new(move |cur| next_interval_start(cur, interval_period)),
+    }
+}
+
+// the start of the bucket of the interval that `time` falls into
+pub fn interval_start(time: &NaiveDateTime, interval_period: &Interval) -> NaiveDateTime {
+    let offset_secs = interval_period.offset_secs as i64;
+    match interval_period.kind {
+        IntervalKind::Fixed => {
+            let secs = time.signed_duration_since(*UNIX_EPOCH).num_seconds() - offset_secs;
+            let bucket_secs = interval_period.bucket_secs as i64;
+            NaiveDateTime::from_timestamp(
+                secs.div_euclid(bucket_secs) * bucket_secs + offset_secs,
+                0,
+            )
+        }
+        IntervalKind::Calendar(unit, tz) => {
+            let date = local_date(time, tz, offset_secs);
+            // a bucket may start later than its date suggests when it starts in a DST gap
+            (0..)
+                .map(|n| calendar_bucket_start(date, unit, -n, tz, offset_secs))
+                .find(|start| start <= time)
+                .unwrap()
+        }
+    }
+}
+
+// the start of the first bucket of the interval after `time`
+pub fn next_interval_start(time: &NaiveDateTime, interval_period: &Interval) -> NaiveDateTime {
+    match interval_period.kind {
+        IntervalKind::Fixed => {
+            interval_start(time, interval_period)
+                + Duration::seconds(interval_period.bucket_secs as i64)
+        }
+        IntervalKind::Calendar(unit, tz) => {
+            let offset_secs = interval_period.offset_secs as i64;
+            let date = local_date(time, tz, offset_secs);
+            (1..)
+                .map(|n| calendar_bucket_start(date, unit, n, tz, offset_secs))
+                .find(|start| start > time)
+                .unwrap()
+        }
+    }
+}
+
+// the local date of `time`, where days start `offset_secs` after midnight
+fn local_date(time: &NaiveDateTime, tz: Tz, offset_secs: i64) -> NaiveDate {
+    (tz.from_utc_datetime(time).naive_local() - Duration::seconds(offset_secs)).date()
+}
+
+// the start in UTC of the calendar bucket `n` buckets after the one containing the local `date`
+fn calendar_bucket_start(
+    date: NaiveDate,
+    unit: CalendarUnit,
+    n: i32,
+    tz: Tz,
+    offset_secs: i64,
+) -> NaiveDateTime {
+    let first_day = match unit {
+        CalendarUnit::Day => date + Duration::days(i64::from(n)),
+        CalendarUnit::Week => {
+            date - Duration::days(i64::from(date.weekday().num_days_from_monday()))
+                + Duration::weeks(i64::from(n))
+        }
+        CalendarUnit::Month => {
+            let months = date.year() * 12 + date.month0() as i32 + n;
+            NaiveDate::from_ymd(months.div_euclid(12), months.rem_euclid(12) as u32 + 1, 1)
+        }
+    };
+    let mut local = first_day.and_hms(0, 0, 0) + Duration::seconds(offset_secs);
+
+    loop {
+        match tz.from_local_datetime(&local) {
+            LocalResult::Single(start) => return start.naive_utc(),
+            // when clocks go back, the bucket starts at the first occurrence
+            LocalResult::Ambiguous(earliest, _) => return earliest.naive_utc(),
+            // when clocks go forward past the start, the bucket starts right after the gap
+            LocalResult::None => local += Duration::minutes(15),
+        }
     }
 }
 
@@ -53,14 +195,124 @@ mod tests {
     use super::*;
     use chrono::Utc;
 
+    fn calendar(unit: CalendarUnit, offset_secs: u64) -> Interval {
+        Interval {
+            name: "calendar".to_owned(),
+            kind: IntervalKind::Calendar(unit, chrono_tz::Europe::Berlin),
+            bucket_secs: 0,
+            points_per_bucket: 60,
+            offset_secs,
+        }
+    }
+
+    fn utc(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_calendar_intervals_across_dst() {
+        // clocks went forward on 2018-03-25 in Berlin, that day is 23 hours long
+        let day = calendar(CalendarUnit::Day, 0);
+        let time = utc("2018-03-25 12:00:00");
+        assert_eq!(interval_start(&time, &day), utc("2018-03-24 23:00:00"));
+        assert_eq!(next_interval_start(&time, &day), utc("2018-03-25 22:00:00"));
+
+        // 02:30 didn't exist that day, the bucket starts right after the gap
+        let day_at_0230 = calendar(CalendarUnit::Day, 2 * 3600 + 1800);
+        assert_eq!(
+            interval_start(&time, &day_at_0230),
+            utc("2018-03-25 01:00:00")
+        );
+
+        // the week of Wednesday 2018-10-10 starts on Monday, in summer time
+        let week = calendar(CalendarUnit::Week, 0);
+        assert_eq!(
+            interval_start(&utc("2018-10-10 10:00:00"), &week),
+            utc("2018-10-07 22:00:00")
+        );
+
+        // clocks went back on 2018-10-28, November starts in winter time
+        let month = calendar(CalendarUnit::Month, 0);
+        let buckets: Vec<_> = interval_buckets(
+            utc("2018-09-30 22:00:00"),
+            utc("2019-01-01 00:00:00"),
+            &month,
+        )
+        .collect();
+        assert_eq!(
+            buckets,
+            vec![
+                (utc("2018-09-30 22:00:00"), utc("2018-10-31 23:00:00")),
+                (utc("2018-10-31 23:00:00"), utc("2018-11-30 23:00:00")),
+                (utc("2018-11-30 23:00:00"), utc("2018-12-31 23:00:00")),
+            ]
+        );
+    }
+
     #[test]
     fn test() {
         let now = Utc::now().naive_utc();
         let time = truncate_seconds(now);
         assert_eq!(time.timestamp_subsec_nanos(), 0u32);
     }
-}
 
-//1538863449575227000
-//1538863449575
-//1538863449
+    #[test]
+    fn test_parse_timestamp_auto() {
+        let expected = NaiveDateTime::from_timestamp(1538863449, 575227000);
+        let auto = TimestampUnit::Auto;
+
+        assert_eq!(
+            parse_timestamp("1538863449575227000", auto).unwrap(),
+            expected
+        );
+        assert_eq!(parse_timestamp("1538863449575227", auto).unwrap(), expected);
+        assert_eq!(
+            parse_timestamp("1538863449575", auto).unwrap(),
+            NaiveDateTime::from_timestamp(1538863449, 575000000)
+        );
+        assert_eq!(
+            parse_timestamp("1538863449", auto).unwrap(),
+            NaiveDateTime::from_timestamp(1538863449, 0)
+        );
+        assert_eq!(
+            parse_timestamp("2018-10-06T22:04:09.575227+00:00", auto).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_parse_timestamp_explicit_unit() {
+        assert_eq!(
+            parse_timestamp("1538863449575", TimestampUnit::Seconds).unwrap(),
+            NaiveDateTime::from_timestamp(1538863449575, 0)
+        );
+        assert_eq!(
+            parse_timestamp("1538863449", TimestampUnit::Nanos).unwrap(),
+            NaiveDateTime::from_timestamp(1, 538863449)
+        );
+    }
+
+    #[test]
+    fn test_format_timestamp_roundtrip() {
+        let ts = NaiveDateTime::from_timestamp(1538863449, 575227000);
+
+        for unit in &[
+            TimestampUnit::Auto,
+            TimestampUnit::Micros,
+            TimestampUnit::Rfc3339,
+        ] {
+            assert_eq!(
+                parse_timestamp(&format_timestamp(&ts, *unit), *unit).unwrap(),
+                ts
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_timestamp_malformed() {
+        assert!(parse_timestamp("", TimestampUnit::Auto).is_err());
+        assert!(parse_timestamp("yesterday", TimestampUnit::Auto).is_err());
+        assert!(parse_timestamp("1538863449", TimestampUnit::Rfc3339).is_err());
+        assert!(parse_timestamp("2018-10-06T22:04:09Z", TimestampUnit::Millis).is_err());
+    }
+}